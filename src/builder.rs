@@ -0,0 +1,219 @@
+use crate::{
+    CancelToken, ConflictPolicy, DiffMode, Fetch, FileSystem, LockPolicy, MergeCache, Mirror,
+    Result, UnrelatedPolicy,
+};
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use url::Url;
+
+/// Builds a [`Fetch`] handle.
+///
+/// Options that are not set keep the same defaults as [`Fetch::new`]. Every field of [`Fetch`]
+/// has a setter of the same name.
+///
+/// ```no_run
+/// use aur_fetch::Fetch;
+///
+/// # fn main() -> aur_fetch::Result<()> {
+/// let fetch = Fetch::builder()
+///     .cache_dir("/var/cache/aur")
+///     .clone_jobs(8)
+///     .seen_ref("refs/mytool/seen")
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FetchBuilder {
+    clone_dir: Option<PathBuf>,
+    diff_dir: Option<PathBuf>,
+    fetch: Fetch,
+}
+
+impl Default for FetchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fetch {
+    /// Creates a [`FetchBuilder`] to configure a new handle.
+    pub fn builder() -> FetchBuilder {
+        FetchBuilder::new()
+    }
+}
+
+impl FetchBuilder {
+    /// Creates a new builder with the default options.
+    pub fn new() -> Self {
+        FetchBuilder {
+            clone_dir: None,
+            diff_dir: None,
+            fetch: Fetch::with_dirs(PathBuf::new(), PathBuf::new()),
+        }
+    }
+
+    /// Builds the handle.
+    ///
+    /// The clone and diff dirs default to the current directory if not set.
+    pub fn build(self) -> Result<Fetch> {
+        let mut fetch = self.fetch;
+        fetch.clone_dir = match self.clone_dir {
+            Some(dir) => dir,
+            None => env::current_dir()?,
+        };
+        fetch.diff_dir = match self.diff_dir {
+            Some(dir) => dir,
+            None => env::current_dir()?,
+        };
+        Ok(fetch)
+    }
+
+    /// Sets the clone and diff dirs to subdirectories of a cache dir.
+    ///
+    /// See [`Fetch::with_cache_dir`].
+    pub fn cache_dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        let path = path.as_ref();
+        self.clone_dir = Some(path.join("clone"));
+        self.diff_dir = Some(path.join("diff"));
+        self
+    }
+
+    /// Sets [`clone_dir`](struct.Fetch.html#structfield.clone_dir).
+    pub fn clone_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.clone_dir = Some(path.into());
+        self
+    }
+
+    /// Sets [`diff_dir`](struct.Fetch.html#structfield.diff_dir).
+    pub fn diff_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.diff_dir = Some(path.into());
+        self
+    }
+
+    /// Sets [`aur_url`](struct.Fetch.html#structfield.aur_url) to the URL of a mirror and
+    /// [`branch_per_package`](struct.Fetch.html#structfield.branch_per_package) to its layout.
+    pub fn mirror(mut self, mirror: Mirror) -> Self {
+        self.fetch.aur_url = mirror.url();
+        self.fetch.branch_per_package = mirror.branch_per_package();
+        self
+    }
+}
+
+/// Generates a setter for each option of [`Fetch`].
+///
+/// `value` setters take the field's type, `into` setters anything that converts to it, `list`
+/// setters anything iterable whose items convert to its elements and `some` setters wrap the
+/// value for optional fields. Every field must be listed or the exhaustive pattern in
+/// `all_options_have_setters` fails to compile.
+macro_rules! setters {
+    ($($kind:ident $field:ident: $ty:ty,)*) => {
+        impl FetchBuilder {
+            $(setters!(@$kind $field: $ty);)*
+        }
+
+        #[allow(dead_code)]
+        fn all_options_have_setters(fetch: Fetch) {
+            let Fetch {
+                clone_dir: _,
+                diff_dir: _,
+                $($field: _,)*
+            } = fetch;
+        }
+    };
+    (@value $field:ident: $ty:ty) => {
+        #[doc = concat!("Sets [`", stringify!($field), "`](struct.Fetch.html#structfield.", stringify!($field), ").")]
+        pub fn $field(mut self, $field: $ty) -> Self {
+            self.fetch.$field = $field;
+            self
+        }
+    };
+    (@into $field:ident: $ty:ty) => {
+        #[doc = concat!("Sets [`", stringify!($field), "`](struct.Fetch.html#structfield.", stringify!($field), ").")]
+        pub fn $field<T: Into<$ty>>(mut self, $field: T) -> Self {
+            self.fetch.$field = $field.into();
+            self
+        }
+    };
+    (@list $field:ident: $ty:ty) => {
+        #[doc = concat!("Sets [`", stringify!($field), "`](struct.Fetch.html#structfield.", stringify!($field), ").")]
+        pub fn $field<T: Into<$ty>, I: IntoIterator<Item = T>>(mut self, $field: I) -> Self {
+            self.fetch.$field = $field.into_iter().map(Into::into).collect();
+            self
+        }
+    };
+    (@some $field:ident: $ty:ty) => {
+        #[doc = concat!("Sets [`", stringify!($field), "`](struct.Fetch.html#structfield.", stringify!($field), ").")]
+        pub fn $field(mut self, $field: $ty) -> Self {
+            self.fetch.$field = Some($field);
+            self
+        }
+    };
+    (@some_into $field:ident: $ty:ty) => {
+        #[doc = concat!("Sets [`", stringify!($field), "`](struct.Fetch.html#structfield.", stringify!($field), ").")]
+        pub fn $field<T: Into<$ty>>(mut self, $field: T) -> Self {
+            self.fetch.$field = Some($field.into());
+            self
+        }
+    };
+    (@some_list $field:ident: $ty:ty) => {
+        #[doc = concat!("Sets [`", stringify!($field), "`](struct.Fetch.html#structfield.", stringify!($field), ").")]
+        pub fn $field<T: Into<$ty>, I: IntoIterator<Item = T>>(mut self, $field: I) -> Self {
+            self.fetch.$field = Some($field.into_iter().map(Into::into).collect());
+            self
+        }
+    };
+}
+
+setters! {
+    into git: PathBuf,
+    list git_flags: String,
+    value aur_url: Url,
+    value branch_per_package: bool,
+    some_into diff_algorithm: String,
+    some find_renames: u32,
+    value find_copies: bool,
+    some_into diff_submodule: String,
+    some max_diff_bytes: usize,
+    some diff_preview_lines: usize,
+    value diff_untracked: bool,
+    list diff_exclude: String,
+    value srcinfo_fallback: bool,
+    value saved_diff_stat: bool,
+    value keep_diff_history: bool,
+    list http_headers: String,
+    some_into proxy: String,
+    some protocol_version: u8,
+    some batch_timeout: Duration,
+    value cancel: CancelToken,
+    some command_timeout: Duration,
+    value retries: u32,
+    value retry_delay: Duration,
+    value clone_jobs: usize,
+    into seen_ref: String,
+    value diff_mode: DiffMode,
+    value use_worktrees: bool,
+    value performance_config: bool,
+    value clone_into_existing: bool,
+    value single_branch: bool,
+    list clone_args: String,
+    list clean_keep: String,
+    some clone_depth: u32,
+    list alternates: PathBuf,
+    value prune: bool,
+    some_list verify_command: String,
+    value record_provenance: bool,
+    value merge_autostash: bool,
+    value merge_jobs: usize,
+    into worker_name: String,
+    some worker_stack_size: usize,
+    value conflict_policy: ConflictPolicy,
+    value unrelated_policy: UnrelatedPolicy,
+    value lock_policy: LockPolicy,
+    value fs: Arc<dyn FileSystem>,
+    value merge_cache: MergeCache,
+}
@@ -1,10 +1,26 @@
-/// Callback called whenever a download completes.
+use crate::RefUpdate;
+
+/// Callback called whenever a package finishes downloading or being verified.
+///
+/// Downloads call it with git's output, while [`accept_cb`](struct.Fetch.html#method.accept_cb)
+/// and [`verify_cb`](struct.Fetch.html#method.verify_cb) call it with the output of the
+/// verify command.
 #[derive(Debug)]
 pub struct Callback<'a> {
     /// The name of the package that completed.
     pub pkg: &'a str,
-    /// The amount of packages that have finished downloading.
+    /// The amount of packages that have finished so far.
     pub n: usize,
-    /// Output of the git command called to download the package.
+    /// Output of the command ran for the package.
     pub output: &'a str,
 }
+
+impl Callback<'_> {
+    /// The refs that were updated by the download.
+    ///
+    /// This is parsed from [`output`](#structfield.output) and is empty for freshly cloned
+    /// packages and verify command output.
+    pub fn ref_updates(&self) -> Vec<RefUpdate> {
+        RefUpdate::parse(self.output)
+    }
+}
@@ -0,0 +1,146 @@
+use crate::fetch::{
+    command_err, kill_group, log_cmd, timeout_err, unique_repos, validate_name, PkgDownload,
+};
+use crate::{Error, Fetch, Repo, Result};
+
+use std::mem;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use tokio::sync::Semaphore;
+
+impl Fetch {
+    /// The same as [`download`](fn.download.html) but runs on a tokio runtime instead of
+    /// blocking threads.
+    ///
+    /// At most [`clone_jobs`](#structfield.clone_jobs) downloads run at once. The first failed
+    /// download stops any further downloads from starting and its error is returned once running
+    /// downloads complete.
+    ///
+    /// Only the clone or fetch itself is async. The quick follow up steps, such as checking out a
+    /// [`Repo::reference`], run on the calling task, as do downloads using
+    /// [`clone_into_existing`](#structfield.clone_into_existing).
+    pub async fn download_async<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<Vec<String>> {
+        self.download_repos_async(&self.pkg_repos(pkgs)).await
+    }
+
+    /// The same as [`download_repos`](fn.download_repos.html) but async. See
+    /// [`download_async`](fn.download_async.html).
+    pub async fn download_repos_async(&self, repos: &[Repo]) -> Result<Vec<String>> {
+        repos
+            .iter()
+            .try_for_each(|repo| validate_name(&repo.name))?;
+        let repos = unique_repos(repos)?;
+        self.warn_case_collisions(&repos);
+
+        let jobs = &Semaphore::new(self.clone_jobs.max(1));
+        let stop = &AtomicBool::new(false);
+        let fetched = &Mutex::new(Vec::with_capacity(repos.len()));
+
+        let downloads = repos.iter().map(|&repo| async move {
+            let _permit = jobs.acquire().await.expect("semaphore is never closed");
+            if stop.load(Ordering::Acquire) || self.cancel.is_cancelled() {
+                return Ok(());
+            }
+            match self.download_pkg_async(repo).await {
+                Ok(download) => {
+                    if download.fetched {
+                        fetched.lock().unwrap().push(repo.name.clone());
+                    }
+                    Ok(())
+                }
+                Err(e) => {
+                    stop.store(true, Ordering::Release);
+                    Err(e)
+                }
+            }
+        });
+        let downloads = join_all(downloads);
+
+        let results = match self.batch_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, downloads).await.ok(),
+            None => Some(downloads.await),
+        };
+        let fetched = mem::take(&mut *fetched.lock().unwrap());
+
+        match results {
+            None => Err(Error::BatchTimedOut { fetched }),
+            Some(_) if self.cancel.is_cancelled() => Err(Error::Cancelled { fetched }),
+            Some(results) => {
+                results.into_iter().collect::<Result<()>>()?;
+                Ok(fetched)
+            }
+        }
+    }
+
+    async fn download_pkg_async(&self, repo: &Repo) -> Result<PkgDownload> {
+        self.mk_clone_dir()?;
+
+        if self.clones_into_existing(&repo.name) {
+            return self.download_existing(repo);
+        }
+
+        let (command, fetched, guard) = self.download_command(repo)?;
+        log_cmd(&command);
+        let mut command = tokio::process::Command::from(command);
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let mut spawn = Duration::ZERO;
+        let mut fetch = Duration::ZERO;
+        let mut attempt = 0;
+
+        let output = loop {
+            let start = Instant::now();
+            let child = command
+                .spawn()
+                .map_err(|e| command_err(command.as_std(), Some(e.to_string())))?;
+            let spawned = start.elapsed();
+            let pid = child.id();
+            let output = async {
+                child
+                    .wait_with_output()
+                    .await
+                    .map_err(|e| command_err(command.as_std(), Some(e.to_string())))
+            };
+            tokio::pin!(output);
+            let output = match self.command_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, &mut output).await {
+                    Ok(output) => output,
+                    Err(_) => {
+                        // Git is killed when the child is dropped but its remote helpers are
+                        // not. This is done first so the pid still leads its group.
+                        if let Some(pid) = pid {
+                            kill_group(pid);
+                        }
+                        Err(timeout_err(command.as_std()))
+                    }
+                },
+                None => output.await,
+            };
+            spawn += spawned;
+            fetch += start.elapsed() - spawned;
+
+            if !self.should_retry(&repo.name, &output, &mut attempt) {
+                break output?;
+            }
+            guard.reset()?;
+            tokio::time::sleep(self.retry_delay).await;
+        };
+
+        let start = Instant::now();
+        let output = self.finish_download(repo, command.as_std(), output, guard, fetched)?;
+        Ok(PkgDownload {
+            fetched,
+            output,
+            spawn,
+            fetch,
+            checkout: start.elapsed(),
+        })
+    }
+}
@@ -42,6 +42,64 @@ pub enum Error {
     CommandFailed(CommandFailed),
     /// An io error occurred.
     Io(io::Error),
+    /// Merging a package failed because of conflicts.
+    ///
+    /// If [`ConflictPolicy::Leave`](enum.ConflictPolicy.html#variant.Leave) is used the repo is
+    /// left mid merge so the conflicts can be resolved. Otherwise the merge is aborted.
+    MergeConflict {
+        /// The package that failed to merge.
+        pkg: String,
+        /// The files that have conflicts.
+        files: Vec<String>,
+    },
+    /// A batch of downloads took longer than the batch timeout.
+    BatchTimedOut {
+        /// The packages that were fetched before the timeout.
+        fetched: Vec<String>,
+    },
+    /// A batch of downloads was cancelled.
+    Cancelled {
+        /// The packages that were fetched before the batch was cancelled.
+        fetched: Vec<String>,
+    },
+    /// A package could not be diffed because it is a shallow clone that is missing the commit
+    /// it was last seen at.
+    ///
+    /// The full history can be fetched with
+    /// [`Fetch::unshallow`](struct.Fetch.html#method.unshallow).
+    ShallowRepo {
+        /// The package that is shallow.
+        pkg: String,
+    },
+    /// A package could not be merged because its history is unrelated to upstream.
+    UnrelatedHistories {
+        /// The package that could not be merged.
+        pkg: String,
+    },
+    /// Multiple repos with different URLs, references or branches would be downloaded into the
+    /// same directory.
+    DuplicateRepo {
+        /// The name of the directory the repos share.
+        name: String,
+    },
+    /// A package name could refer to a path outside of the cache or be taken as an option.
+    InvalidPackageName(String),
+    /// A revision given to diff does not name a commit in the package.
+    UnknownRevision {
+        /// The package the revision was looked up in.
+        pkg: String,
+        /// The revision that does not exist.
+        rev: String,
+    },
+    /// A command ran for longer than the command timeout and was killed.
+    Timeout {
+        /// The command that was ran.
+        command: PathBuf,
+        /// Args passed to the command that was ran.
+        args: Vec<String>,
+    },
+    /// The git binary could not be executed.
+    GitNotFound(PathBuf),
 }
 
 impl Display for Error {
@@ -51,6 +109,34 @@ impl Display for Error {
         match self {
             CommandFailed(e) => e.fmt(fmt),
             Io(e) => e.fmt(fmt),
+            MergeConflict { pkg, files } => {
+                write!(fmt, "merge conflict in {}: {}", pkg, files.join(", "))
+            }
+            BatchTimedOut { .. } => write!(fmt, "timed out downloading packages"),
+            Cancelled { .. } => write!(fmt, "downloading packages was cancelled"),
+            ShallowRepo { pkg } => write!(
+                fmt,
+                "{} is a shallow clone missing its seen commit and must be unshallowed to diff",
+                pkg
+            ),
+            UnrelatedHistories { pkg } => write!(
+                fmt,
+                "{} has history unrelated to upstream, it may have been force pushed",
+                pkg
+            ),
+            DuplicateRepo { name } => {
+                write!(fmt, "multiple repos would be downloaded into {}", name)
+            }
+            InvalidPackageName(pkg) => write!(fmt, "invalid package name: {}", pkg),
+            UnknownRevision { pkg, rev } => write!(fmt, "{} has no revision {}", pkg, rev),
+            Timeout { command, args } => {
+                write!(fmt, "command timed out: {}", command.display())?;
+                for arg in args {
+                    write!(fmt, " {}", arg)?;
+                }
+                Ok(())
+            }
+            GitNotFound(git) => write!(fmt, "could not run git: {}", git.display()),
         }
     }
 }
@@ -1,18 +1,40 @@
-use crate::{Callback, CommandFailed, Error};
+use crate::{Callback, CommandFailed, Error, FileSystem, Provenance, RealFs};
 
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::env::{self, current_dir};
-use std::ffi::OsStr;
-use std::fs::{create_dir_all, File};
-use std::io::{self, Write};
-use std::os::unix::fs::symlink;
-use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
+use std::ffi::{OsStr, OsString};
+use std::fs::{
+    self, create_dir_all, metadata, read_dir, read_to_string, remove_dir_all, remove_file, rename,
+    File, OpenOptions,
+};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{is_separator, Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crossbeam::channel;
+use crossbeam::channel::{self, RecvTimeoutError};
 use url::Url;
 
-static SEEN: &str = "AUR_SEEN";
+static PERFORMANCE_CONFIG: &[&str] = &[
+    "-c",
+    "core.untrackedCache=true",
+    "-c",
+    "feature.manyFiles=true",
+];
+
+/// How often a batch of downloads checks whether it has been cancelled.
+static CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// The ref a pinned repo's [`Repo::reference`] is fetched into.
+static PINNED_REF: &str = "refs/aur-fetch/pinned";
 
 /// Result type for this crate;
 pub type Result<T> = std::result::Result<T, Error>;
@@ -23,448 +45,4087 @@ pub struct Repo {
     pub url: Url,
     /// The name of the git repo.
     pub name: String,
+    /// A branch, tag or commit to follow instead of the default branch.
+    ///
+    /// The reference is fetched every time the repo is downloaded and the checked out branch
+    /// tracks it in place of the default branch, so diffing, merging and the upstream HEAD all
+    /// use the pinned commit. A repo is checked out at the pinned commit when it is first
+    /// pinned. Downloading it again without a reference goes back to following the default
+    /// branch.
+    pub reference: Option<String>,
+    /// The branch holding the package, for mirrors that keep every package in one repo.
+    ///
+    /// Only this branch is downloaded and the checked out branch, which is still named
+    /// `master`, tracks it.
+    pub branch: Option<String>,
 }
 
-/// Handle to the current configuration.
+/// When to color diffs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    /// Always color diffs.
+    Always,
+    /// Never color diffs.
+    Never,
+    /// Color diffs if stdout is a terminal.
+    Auto,
+}
+
+impl From<bool> for Color {
+    fn from(color: bool) -> Self {
+        if color {
+            Color::Always
+        } else {
+            Color::Never
+        }
+    }
+}
+
+/// What to do when merging a package conflicts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Leave the package in the conflicted state so the conflicts can be resolved by hand.
+    Leave,
+    /// Abort the merge, putting the package back how it was before merging.
+    #[default]
+    Abort,
+    /// Abort the merge and reset the package to the upstream HEAD.
+    ///
+    /// This discards any local commits and changes.
+    Theirs,
+}
+
+/// What to do when merging a package whose history is unrelated to upstream.
 ///
-/// This handle is used to configure parts of the fetching process. All the features of this crate
-/// must be done through this handle.
-#[derive(Clone, Debug)]
-pub struct Fetch {
-    /// The directory to place AUR packages in.
-    pub clone_dir: PathBuf,
-    /// The directory to place diffs in.
-    pub diff_dir: PathBuf,
-    /// The git command to run.
-    pub git: PathBuf,
-    /// Flags passed to git.
-    pub git_flags: Vec<String>,
-    /// The AUR URL.
-    pub aur_url: Url,
+/// This usually happens when upstream history was rewritten with a force push.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UnrelatedPolicy {
+    /// Fail with [`Error::UnrelatedHistories`](enum.Error.html#variant.UnrelatedHistories).
+    #[default]
+    Error,
+    /// Reset the package to the upstream HEAD, discarding any local commits and changes.
+    Reset,
 }
 
-fn command_err(cmd: &Command, stderr: Option<String>) -> Error {
-    Error::CommandFailed(CommandFailed {
-        dir: cmd.get_current_dir().unwrap().to_owned(),
-        command: cmd.get_program().to_owned().into(),
-        args: cmd
-            .get_args()
-            .map(|s| s.to_string_lossy().into_owned())
-            .collect(),
-        stderr,
-    })
+/// What to do when a git operation fails because a lock file such as `index.lock` already exists.
+///
+/// Git leaves its lock files behind when it is killed mid operation, after which every
+/// operation on the repo fails until the lock is removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    /// Fail with the error from git.
+    #[default]
+    Fail,
+    /// Wait up to the given duration for the lock to be released, then retry the operation.
+    Wait(Duration),
+    /// Wait for the lock to be released, removing it once it is older than the given duration.
+    ///
+    /// Git does not record which process holds a lock so only its age is checked. Removing a
+    /// lock that is still held can corrupt the repo, so the duration should be far longer than
+    /// any git operation takes.
+    RemoveStale(Duration),
 }
 
-impl Fetch {
-    /// Create a new Handle with working defaults.
+/// How the changes since a package was last seen are diffed.
+///
+/// Packages that have never been seen are always diffed against an empty tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DiffMode {
+    /// Merge the upstream HEAD into AUR_SEEN and diff the result against AUR_SEEN.
     ///
-    /// This Inializes the clone and diff dir to the current dirrectory. If you want to configure
-    /// a cache directory you will need to do that yourself.
-    pub fn new() -> Result<Self> {
-        Ok(Self {
-            clone_dir: env::current_dir()?,
-            diff_dir: env::current_dir()?,
-            git: "git".into(),
-            git_flags: Vec::new(),
-            aur_url: "https://aur.archlinux.org".parse().unwrap(),
-        })
-    }
+    /// This requires temporarily resetting the working tree unless
+    /// [`use_worktrees`](struct.Fetch.html#structfield.use_worktrees) is set.
+    #[default]
+    Merge,
+    /// Diff AUR_SEEN directly against the upstream HEAD.
+    ///
+    /// This never touches the working tree but if upstream history was rewritten the diff may
+    /// include changes that were already seen.
+    Range,
+}
 
-    /// Create a new handle with a specified cache dir.
+/// A host serving AUR package repos.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mirror {
+    /// The official AUR at aur.archlinux.org.
+    Aur,
+    /// The GitHub mirror at github.com/archlinux/aur, one repo with a branch per package.
+    GitHub,
+    /// A mirror serving each package's git repo at `<url>/<pkg>`.
+    Custom(Url),
+}
+
+impl Mirror {
+    /// The base URL packages are downloaded from.
     ///
-    /// clone_dir will be a subdirectory named clone inside of the specified path.
-    /// diff_dir will be a subdirectory named diff inside of the specified path.
-    pub fn with_cache_dir<P: AsRef<Path>>(path: P) -> Self {
-        let path = path.as_ref();
+    /// For mirrors with a branch per package this is the URL of the single repo.
+    pub fn url(&self) -> Url {
+        match self {
+            Mirror::Aur => "https://aur.archlinux.org".parse().unwrap(),
+            Mirror::GitHub => "https://github.com/archlinux/aur.git".parse().unwrap(),
+            Mirror::Custom(url) => url.clone(),
+        }
+    }
 
-        Self {
-            clone_dir: path.join("clone"),
-            diff_dir: path.join("diff"),
-            git: "git".into(),
-            git_flags: Vec::new(),
-            aur_url: "https://aur.archlinux.org".parse().unwrap(),
+    /// Whether the mirror is one repo with a branch per package.
+    pub fn branch_per_package(&self) -> bool {
+        matches!(self, Mirror::GitHub)
+    }
+}
+
+/// Timings collected over a batch of downloads.
+///
+/// Durations other than `total` are summed across every package, so with concurrent downloads
+/// they may exceed `total`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchTimings {
+    /// The amount of packages that were downloaded.
+    pub packages: usize,
+    /// Wall clock time taken by the whole batch.
+    pub total: Duration,
+    /// Time spent spawning git to clone or fetch.
+    pub spawn: Duration,
+    /// Time spent waiting for git to clone or fetch, which is mostly network transfer.
+    pub fetch: Duration,
+    /// Time spent checking out newly cloned packages and setting up packages after downloading.
+    pub checkout: Duration,
+    /// Whether the batch was stopped early by the batch timeout.
+    pub timed_out: bool,
+    /// Whether the batch was stopped early because it was cancelled.
+    pub cancelled: bool,
+}
+
+impl BatchTimings {
+    fn stopped(&self) -> bool {
+        self.timed_out || self.cancelled
+    }
+
+    fn stopped_err(&self, fetched: Vec<String>) -> Error {
+        if self.cancelled {
+            Error::Cancelled { fetched }
+        } else {
+            Error::BatchTimedOut { fetched }
         }
     }
+}
 
-    /// Create a new handle with a specified cache dir.
+/// A handle for cancelling downloads from another thread.
+///
+/// Clones share the same state so a clone can be kept and cancelled, for example from a signal
+/// handler, while the [`Fetch`] it was given to is downloading.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a new token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels any downloads using this token.
     ///
-    ///Both diffs and cloned packages will be places in the provided dir.
-    pub fn with_combined_cache_dir<P: AsRef<Path>>(path: P) -> Self {
-        let path = path.as_ref();
+    /// The token stays cancelled until [`reset`](#method.reset) is called.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
 
-        Self {
-            clone_dir: path.into(),
-            diff_dir: path.into(),
-            git: "git".into(),
-            git_flags: Vec::new(),
-            aur_url: "https://aur.archlinux.org".parse().unwrap(),
+    /// Whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Clears the cancellation so the token can be used again.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Whether a package was cloned or fetched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloneOrFetch {
+    /// The package was not in the cache and was freshly cloned.
+    Cloned,
+    /// The package was already in the cache and was fetched.
+    Fetched,
+}
+
+/// The result of downloading a package with
+/// [`Fetch::download_detailed`](struct.Fetch.html#method.download_detailed) or
+/// [`Fetch::download_all`](struct.Fetch.html#method.download_all).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadResult {
+    /// The name of the package.
+    pub name: String,
+    /// Whether the package was cloned or fetched.
+    pub action: CloneOrFetch,
+    /// The output of git.
+    pub output: String,
+}
+
+impl DownloadResult {
+    fn new(pkg: &str, download: &PkgDownload) -> Self {
+        let action = if download.fetched {
+            CloneOrFetch::Fetched
+        } else {
+            CloneOrFetch::Cloned
+        };
+        DownloadResult {
+            name: pkg.to_string(),
+            action,
+            output: String::from_utf8_lossy(&download.output).trim().to_string(),
         }
     }
+}
 
-    /// Downloads a list of packages to the cache dir.
-    ///
-    /// This downloads packages from the AUR using git. New packages will be cloned, while packages
-    /// that already exist in cache will be fetched. Merging will need to be done in a separate
-    /// step.
-    ///
-    /// Each package is downloaded concurrently which givess a major speedup
-    ///
-    /// Depending on how many packages are being downloaded and connection speed this
-    /// function may take a little while to complete. See [`download_cb`](fn.download_cb.html) if
-    /// you wish track the progress of each download.
-    ///
-    /// This also filters the input list to packages that were already in cache. This filtered list
-    /// can then be passed on to [`merge`](fn.merge.html) as freshly cloned packages will
-    /// not need to be merged.
-    pub fn download<S: AsRef<str> + Send + Sync>(&self, pkgs: &[S]) -> Result<Vec<String>> {
-        self.download_cb(pkgs, |_| ())
+/// The result of downloading packages with
+/// [`Fetch::download_cb_partial`](struct.Fetch.html#method.download_cb_partial).
+#[derive(Debug, Default)]
+pub struct PartialDownload {
+    /// The packages that were already cloned and were fetched.
+    pub fetched: Vec<String>,
+    /// The packages that failed to download and the errors they failed with.
+    pub failed: Vec<(String, Error)>,
+}
+
+pub(crate) struct PkgDownload {
+    pub(crate) fetched: bool,
+    pub(crate) output: Vec<u8>,
+    pub(crate) spawn: Duration,
+    pub(crate) fetch: Duration,
+    pub(crate) checkout: Duration,
+}
+
+/// The upstream HEAD of a set of packages at a point in time.
+///
+/// Created by [`Fetch::snapshot`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpstreamSnapshot {
+    /// Maps each package to the commit its upstream HEAD pointed to.
+    pub heads: HashMap<String, String>,
+}
+
+/// The review state of a set of packages.
+///
+/// Created by [`Fetch::export_state`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReviewState {
+    /// Maps each package to the commit it was last seen at.
+    pub seen: HashMap<String, String>,
+}
+
+/// A summary of the state of a package.
+///
+/// Created by [`Fetch::status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackageStatus {
+    /// The package has not been cloned.
+    NotCloned,
+    /// The package has merged all upstream changes.
+    UpToDate,
+    /// Upstream has changes that have not been seen.
+    UpdatesAvailable {
+        /// The number of upstream commits not yet merged.
+        commits: usize,
+    },
+    /// Upstream has changes that have been seen but not merged.
+    Reviewed,
+    /// The package has uncommitted changes to tracked files.
+    LocalChanges,
+}
+
+/// A report of the state of a package.
+///
+/// Created by [`Fetch::batch_status`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StatusReport {
+    /// The name of the package.
+    pub pkg: String,
+    /// Whether the package is cloned.
+    pub cached: bool,
+    /// Whether the package has a diff. See [`Fetch::has_diff`].
+    pub has_diff: bool,
+    /// Whether the package has not been seen. See [`Fetch::unseen`].
+    pub unseen: bool,
+    /// The commit hash of the package's upstream HEAD.
+    pub upstream_head: Option<String>,
+    /// The number of upstream commits not yet seen. See [`Fetch::commits_behind`].
+    pub commits_behind: usize,
+}
+
+/// A package in the clone dir.
+///
+/// Created by [`Fetch::iter_packages`]. Each status method runs git when called.
+#[derive(Clone, Debug)]
+pub struct CachedPackage<'a> {
+    fetch: &'a Fetch,
+    name: String,
+}
+
+impl CachedPackage<'_> {
+    /// The name of the package.
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    /// The same as [`download`](fn.download.html) but calls a Callback after each download.
-    ///
-    /// The callback is called each time a package download is completed.
-    pub fn download_cb<S: AsRef<str> + Send + Sync, F: Fn(Callback)>(
-        &self,
-        pkgs: &[S],
-        f: F,
-    ) -> Result<Vec<String>> {
-        let repos = pkgs
-            .iter()
-            .map(|p| {
-                let mut url = self.aur_url.clone();
-                url.set_path(p.as_ref());
-                Repo {
-                    url,
-                    name: p.as_ref().to_string(),
-                }
-            })
-            .collect::<Vec<_>>();
-        self.download_repos_cb(&repos, f)
+    /// The path to the package's git repo.
+    pub fn path(&self) -> PathBuf {
+        // The name was read from the clone dir so it can not refer to anywhere else.
+        self.fetch.clone_dir.join(&self.name)
     }
 
-    /// The same as [`download`](fn.download.html) but downloads a specified list of repos instead of AUR packages.
-    pub fn download_repos<F: Fn(Callback)>(&self, repos: &[Repo]) -> Result<Vec<String>> {
-        self.download_repos_cb(repos, |_| ())
+    /// Check if the package has a diff. See [`Fetch::has_diff`].
+    pub fn has_diff(&self) -> Result<bool> {
+        git_has_diff(
+            &self.fetch.git,
+            &self.fetch.flags(),
+            self.path(),
+            &self.fetch.seen_ref,
+        )
     }
 
-    /// The same as [`download_repos`](fn.download_repos.html) but calls a Callback after each download.
-    ///
-    /// The callback is called each time a package download is completed.
-    pub fn download_repos_cb<F: Fn(Callback)>(&self, repos: &[Repo], f: F) -> Result<Vec<String>> {
-        let (pkg_send, pkg_rec) = channel::bounded(0);
-        let (fetched_send, fetched_rec) = channel::bounded(32);
-        let f = &f;
-        let stop = &AtomicBool::new(false);
-        let mut fetched = Vec::with_capacity(repos.len());
+    /// Check if the package has not been seen. See [`Fetch::unseen`].
+    pub fn is_unseen(&self) -> Result<bool> {
+        git_unseen(
+            &self.fetch.git,
+            &self.fetch.flags(),
+            self.path(),
+            &self.fetch.seen_ref,
+        )
+    }
 
-        std::thread::scope(|scope| {
-            scope.spawn(move || {
-                for repo in repos {
-                    if pkg_send.send(repo).is_err() {
-                        break;
-                    }
-                }
-            });
+    /// The commit hash of the package's local HEAD.
+    pub fn head(&self) -> Result<String> {
+        git_head(&self.fetch.git, &self.fetch.flags(), self.path())
+    }
 
-            for _ in 0..20.min(repos.len()) {
-                let fetched_send = fetched_send.clone();
-                let pkg_rec = pkg_rec.clone();
-                scope.spawn(move || {
-                    for repo in &pkg_rec {
-                        if stop.load(Ordering::Acquire) {
-                            break;
-                        }
-                        match self.download_pkg(&repo.url, &repo.name) {
-                            Ok((fetched, out)) => {
-                                let _ = fetched_send.send(Ok((repo.name.clone(), fetched, out)));
-                            }
-                            Err(e) => {
-                                stop.store(true, Ordering::Release);
-                                let _ = fetched_send.send(Err(e));
-                                break;
-                            }
-                        }
-                    }
-                });
-            }
+    /// The commit hash of the package's upstream HEAD.
+    pub fn upstream_head(&self) -> Result<String> {
+        self.fetch.upstream_head(&self.name)
+    }
+}
 
-            drop(pkg_rec);
-            drop(fetched_send);
+/// Gets the lock file from git's stderr if the command failed because the lock already exists.
+fn lock_file(stderr: &str) -> Option<PathBuf> {
+    let (_, rest) = stderr.split_once("Unable to create '")?;
+    let (lock, _) = rest.split_once("': File exists")?;
+    Some(PathBuf::from(lock))
+}
 
-            for (n, msg) in fetched_rec.into_iter().enumerate() {
-                let (pkg, was_fetched, out) = msg?;
-                f(Callback {
-                    pkg: &pkg,
-                    n: n + 1,
-                    output: String::from_utf8_lossy(&out).trim(),
-                });
-                if was_fetched {
-                    fetched.push(pkg)
-                }
-            }
+/// Whether git's stderr looks like the command failed because of a network error.
+fn is_network_error(stderr: &[u8]) -> bool {
+    const ERRORS: &[&str] = &[
+        "could not resolve host",
+        "failed to connect",
+        "connection refused",
+        "connection reset",
+        "connection timed out",
+        "operation timed out",
+        "the remote end hung up",
+        "early eof",
+        "rpc failed",
+        "unable to access",
+    ];
 
-            Ok(fetched)
-        })
+    let stderr = String::from_utf8_lossy(stderr).to_lowercase();
+    ERRORS.iter().any(|e| stderr.contains(e))
+}
+
+/// Which parts of a diff git outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DiffFormat {
+    /// Only the patch.
+    Patch,
+    /// Only the diffstat.
+    Stat,
+    /// The diffstat followed by the patch.
+    StatAndPatch,
+}
+
+/// How the merge for [`DiffMode::Merge`] is done.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MergeMethod {
+    /// Merge in the package's working tree, resetting it afterwards.
+    Reset,
+    /// Merge in a scratch worktree.
+    Worktree,
+    /// Merge with `git merge-tree` without any working tree.
+    MergeTree,
+}
+
+/// Parses a git remote as a URL.
+///
+/// Along with URLs git accepts local paths, relative to the repo at `dir`, and the scp-like
+/// `[user@]host:path` syntax, which is taken to be `ssh://[user@]host/path`.
+fn parse_remote(dir: &Path, remote: &str) -> Option<Url> {
+    if remote.contains("://") {
+        return Url::parse(remote).ok();
+    }
+    match remote.split_once(':') {
+        // A slash before the colon makes it a path.
+        Some((host, path)) if !host.contains('/') => {
+            Url::parse(&format!("ssh://{}/{}", host, path.trim_start_matches('/'))).ok()
+        }
+        _ => Url::from_file_path(dir.join(remote)).ok(),
     }
+}
 
-    fn download_pkg<S: AsRef<str>>(&self, url: &Url, dir: S) -> Result<(bool, Vec<u8>)> {
-        self.mk_clone_dir()?;
+/// Writes everything written to it to each of its writers.
+struct Tee<'a>(Vec<Box<dyn Write + 'a>>);
 
-        let dir = dir.as_ref();
-        let is_git_repo = self.is_git_repo(dir);
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for out in &mut self.0 {
+            out.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
 
-        let mut command = Command::new(&self.git);
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.iter_mut().try_for_each(|out| out.flush())
+    }
+}
 
-        let fetched = if is_git_repo {
-            command.current_dir(self.clone_dir.join(dir));
-            command.args(["fetch", "-v"]);
-            true
-        } else {
-            command.current_dir(&self.clone_dir);
-            command.args(["clone", "--no-progress", "--", url.as_str(), dir]);
-            false
+/// Removes a package directory that is being cloned into unless the clone completes.
+///
+/// This stops interrupted or failed clones from leaving behind half cloned directories that
+/// would break future downloads.
+pub(crate) struct PartialClone(Option<PathBuf>);
+
+impl PartialClone {
+    fn finish(mut self) {
+        self.0 = None;
+    }
+
+    /// Removes what was cloned so far so the clone can be retried.
+    pub(crate) fn reset(&self) -> io::Result<()> {
+        match self.0.as_ref().map(remove_dir_all) {
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for PartialClone {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = remove_dir_all(path);
+        }
+    }
+}
+
+/// Handle to the current configuration.
+///
+/// This handle is used to configure parts of the fetching process. All the features of this crate
+/// must be done through this handle.
+#[derive(Clone, Debug)]
+pub struct Fetch {
+    /// The directory to place AUR packages in.
+    pub clone_dir: PathBuf,
+    /// The directory to place diffs in.
+    pub diff_dir: PathBuf,
+    /// The git command to run.
+    pub git: PathBuf,
+    /// Flags passed to git.
+    pub git_flags: Vec<String>,
+    /// The AUR URL.
+    ///
+    /// Packages are downloaded from `<aur_url>/<pkg>`, so this can be pointed at a mirror
+    /// serving the same layout, including one under a subpath such as
+    /// `https://example.com/aur/`.
+    pub aur_url: Url,
+    /// Whether aur_url is a single repo with a branch per package, like the GitHub mirror.
+    ///
+    /// Each package is then cloned with `--single-branch --branch <pkg>` from aur_url itself.
+    pub branch_per_package: bool,
+    /// The algorithm git uses to generate diffs, such as `histogram` or `patience`.
+    ///
+    /// When unset git's configured default is used.
+    pub diff_algorithm: Option<String>,
+    /// Detect renames in diffs, treating files at least this percent similar as renamed.
+    ///
+    /// This passes `-M<n>%` to git diff. When unset git's configured default is used.
+    pub find_renames: Option<u32>,
+    /// Detect copies in diffs.
+    ///
+    /// This passes `-C` to git diff, which also enables rename detection.
+    pub find_copies: bool,
+    /// How changes to submodules are shown in diffs, one of `short`, `log` or `diff`.
+    ///
+    /// This passes `--submodule=<format>` to git diff. `log` lists the commits the submodule
+    /// moved across and `diff` shows the changes to its contents, rather than just the old and new
+    /// commit hashes. When unset git's configured default is used.
+    pub diff_submodule: Option<String>,
+    /// The maximum size in bytes of diffs returned by [`diff`](struct.Fetch.html#method.diff).
+    ///
+    /// Larger diffs are truncated. When unset diffs are never truncated.
+    pub max_diff_bytes: Option<usize>,
+    /// The maximum amount of lines shown for each file in diffs returned by
+    /// [`diff`](struct.Fetch.html#method.diff).
+    ///
+    /// The rest of each file's changes are replaced with a line saying how many lines were
+    /// left out. This is useful for a quick preview of a package's changes.
+    pub diff_preview_lines: Option<usize>,
+    /// Include files that are not tracked by git in diffs.
+    ///
+    /// This shows files added to a package by the user as new files. Files ignored by git are
+    /// not included but build artifacts that are not ignored will be. The paths in
+    /// [`diff_exclude`](#structfield.diff_exclude) are left out as they are for tracked files.
+    pub diff_untracked: bool,
+    /// Paths left out of diffs.
+    ///
+    /// Each path is passed to git diff as a `:!<path>` pathspec so globs such as `*.lock` may be
+    /// used. Defaults to `.SRCINFO` as it repeats the PKGBUILD.
+    pub diff_exclude: Vec<String>,
+    /// Show changes to the paths in [`diff_exclude`](#structfield.diff_exclude) when nothing
+    /// else changed.
+    ///
+    /// .SRCINFO is normally left out of diffs. This means a package whose only change is to its
+    /// .SRCINFO would show an empty diff, hiding changes such as version bumps that were made
+    /// without editing the PKGBUILD.
+    pub srcinfo_fallback: bool,
+    /// Include the diffstat in diffs saved by [`save_diffs`](struct.Fetch.html#method.save_diffs).
+    ///
+    /// Disable this to save only the log and the patch.
+    pub saved_diff_stat: bool,
+    /// Keep a timestamped copy of each diff saved by
+    /// [`save_diffs`](struct.Fetch.html#method.save_diffs).
+    ///
+    /// Copies are kept in `<diff_dir>/.history/<pkg>/` and can be listed with
+    /// [`diff_history`](struct.Fetch.html#method.diff_history).
+    pub keep_diff_history: bool,
+    /// Extra HTTP headers sent when cloning and fetching, such as `Authorization: Bearer ...`.
+    ///
+    /// Headers are passed to git through `GIT_CONFIG_COUNT` and friends rather than its
+    /// arguments so they are not exposed in the process list or errors. This needs git 2.31.
+    pub http_headers: Vec<String>,
+    /// The proxy used when cloning and fetching over HTTP, such as `http://proxy:8080`.
+    ///
+    /// This is passed to git as `http.proxy` so it works even when git is run without the
+    /// `http_proxy` environment variables. Like the headers it is passed through the
+    /// environment as it may contain credentials.
+    pub proxy: Option<String>,
+    /// The git transfer protocol version used when cloning and fetching.
+    ///
+    /// Version 2 is faster for repos with many refs while 0 or 1 can be used to work around
+    /// servers that misbehave with newer versions. When unset git's default is used.
+    pub protocol_version: Option<u8>,
+    /// The maximum time a batch of downloads may take.
+    ///
+    /// Once exceeded no further downloads are started and
+    /// [`Error::BatchTimedOut`](enum.Error.html#variant.BatchTimedOut) is returned. Downloads
+    /// that are already running are allowed to finish first.
+    pub batch_timeout: Option<Duration>,
+    /// Cancels downloads when cancelled.
+    ///
+    /// Once cancelled no further downloads are started, the git commands of downloads that are
+    /// already running are killed along with any processes they started and
+    /// [`Error::Cancelled`](enum.Error.html#variant.Cancelled) is returned.
+    pub cancel: CancelToken,
+    /// The maximum time each git command may run for.
+    ///
+    /// Commands that run for longer are killed along with any processes they started and fail
+    /// with [`Error::Timeout`](enum.Error.html#variant.Timeout). This includes diffs shown
+    /// through a pager by [`print_diff`](#method.print_diff), so it should be set high enough
+    /// to read them or left unset for interactive use.
+    pub command_timeout: Option<Duration>,
+    /// How many times to retry a download that failed because of a network error or timed out.
+    ///
+    /// Failures that are not network related, such as a broken repo, are not retried.
+    pub retries: u32,
+    /// How long to wait before retrying a failed download.
+    pub retry_delay: Duration,
+    /// The maximum amount of packages to download at once.
+    ///
+    /// 0 and 1 both download packages one at a time.
+    pub clone_jobs: usize,
+    /// The ref used to record the commit each package was last seen at.
+    ///
+    /// Tools sharing a clone dir can each use their own ref, such as `refs/mytool/seen`, to keep
+    /// their review state separate.
+    pub seen_ref: String,
+    /// How the changes since a package was last seen are diffed.
+    pub diff_mode: DiffMode,
+    /// Merge in a separate worktree when diffing with [`DiffMode::Merge`].
+    ///
+    /// By default the package's working tree is temporarily reset to generate the diff. When
+    /// set a scratch worktree kept in the package's git dir is used instead, so diffing never
+    /// touches the working tree and can safely run alongside other queries. Concurrent diffs of
+    /// the same package each take their own worktree from a pool that is reused between calls.
+    /// This uses extra disk space for each package diffed.
+    pub use_worktrees: bool,
+    /// Pass config to git that speeds up operations on large repos.
+    ///
+    /// This sets `core.untrackedCache=true` and `feature.manyFiles=true`, which requires git
+    /// 2.24 or newer.
+    pub performance_config: bool,
+    /// Allow cloning into package directories that already exist.
+    ///
+    /// Normally git refuses to clone into a directory that is not empty. When set the repo is
+    /// instead set up with `git init` and the upstream fetched into it, keeping any existing
+    /// files that do not conflict with upstream.
+    pub clone_into_existing: bool,
+    /// Clone only the upstream's default branch.
+    ///
+    /// This passes `--single-branch` to `git clone`, which sets the fetch refspec to that
+    /// branch. Later fetches then also only fetch that branch, fetching other branches requires
+    /// changing `remote.origin.fetch` in the package's repo. Only applies to new clones.
+    pub single_branch: bool,
+    /// Extra args passed to `git clone`.
+    ///
+    /// These can be used to pass options that do not have a dedicated field such as `--filter`.
+    /// They are only used for new clones and not when cloning into an existing directory.
+    pub clone_args: Vec<String>,
+    /// Patterns of untracked files that [`clean_untracked`](#method.clean_untracked) keeps.
+    ///
+    /// These use the same syntax as `.gitignore`, such as `*.pkg.tar.zst`.
+    pub clean_keep: Vec<String>,
+    /// Only download this many commits of history when cloning and fetching.
+    ///
+    /// Shallow packages are always diffed as with [`DiffMode::Range`] as the history needed to
+    /// merge may be missing. Packages cloned shallow can be given their full history with
+    /// [`unshallow`](#method.unshallow).
+    pub clone_depth: Option<u32>,
+    /// Object directories to share objects from when downloading.
+    ///
+    /// These are passed to git with `GIT_ALTERNATE_OBJECT_DIRECTORIES` so objects already in
+    /// them are not downloaded again. They are also added to each package's
+    /// `objects/info/alternates` as the package relies on them from then on, so they must not be
+    /// removed while any package uses them. Paths should be absolute.
+    pub alternates: Vec<PathBuf>,
+    /// Remove remote tracking refs that no longer exist upstream when fetching.
+    ///
+    /// This passes `--prune` to `git fetch`.
+    pub prune: bool,
+    /// A command that must pass before a package is marked as seen.
+    ///
+    /// The first element is the program and the rest are its args. It is ran in the package's
+    /// directory by [`mark_seen`](#method.mark_seen) and [`accept`](#method.accept), which fail
+    /// with the command's output if it exits non 0. This can be used to enforce review policies
+    /// such as running `namcap` on the PKGBUILD. Like git it is killed after
+    /// [`command_timeout`](#structfield.command_timeout).
+    pub verify_command: Option<Vec<String>>,
+    /// Record where and when each package was downloaded.
+    ///
+    /// After each download the URL, time and upstream commit are written to
+    /// `<diff_dir>/<pkg>.aur-fetch.json`. They can be read back with
+    /// [`provenance`](#method.provenance).
+    pub record_provenance: bool,
+    /// Keep uncommitted changes when merging.
+    ///
+    /// By default uncommitted changes are discarded with `git reset --hard` before merging.
+    /// When set the merge instead uses `git rebase --autostash`, which stashes the changes and
+    /// reapplies them after the merge.
+    pub merge_autostash: bool,
+    /// The maximum amount of packages to merge at once.
+    ///
+    /// 0 and 1 both merge packages one at a time in the order given.
+    pub merge_jobs: usize,
+    /// The name given to worker threads.
+    ///
+    /// Each worker is named `<worker_name>-<n>`, making them easy to find in debuggers and
+    /// panic messages.
+    pub worker_name: String,
+    /// The stack size in bytes of worker threads.
+    ///
+    /// When unset the default stack size of the standard library is used.
+    pub worker_stack_size: Option<usize>,
+    /// What to do when merging a package conflicts.
+    ///
+    /// With [`ConflictPolicy::Leave`] and [`ConflictPolicy::Abort`] the merge fails with
+    /// [`Error::MergeConflict`](enum.Error.html#variant.MergeConflict). With
+    /// [`ConflictPolicy::Theirs`] the merge succeeds with the package reset to upstream.
+    ///
+    /// Defaults to [`ConflictPolicy::Abort`] so a failed merge never leaves the package mid
+    /// rebase.
+    pub conflict_policy: ConflictPolicy,
+    /// What to do when merging a package whose history is unrelated to upstream.
+    pub unrelated_policy: UnrelatedPolicy,
+    /// What to do when merging, resetting or diffing a package fails because git left a lock
+    /// file behind.
+    pub lock_policy: LockPolicy,
+    /// The filesystem used for the diff dir, views and download manifests.
+    ///
+    /// The clone dir always uses the real filesystem. See [`FileSystem`].
+    pub fs: Arc<dyn FileSystem>,
+    /// Trees computed when diffing, reused while AUR_SEEN and the upstream HEAD stay the same.
+    ///
+    /// Clones of a handle share the same cache. Handles built with struct update syntax can
+    /// take a fresh cache with `MergeCache::default()`.
+    pub merge_cache: MergeCache,
+}
+
+/// Caches the tree produced by merging upstream into AUR_SEEN.
+///
+/// Computing this tree requires resetting and merging the working tree so the result is kept
+/// around for as long as AUR_SEEN and the upstream HEAD stay the same. Only the most recently
+/// computed trees are kept, 256 by default.
+///
+/// The merged trees are not referenced by anything so may be removed by `git gc`. Such trees
+/// are computed again when they are next needed.
+#[derive(Clone, Debug)]
+pub struct MergeCache(Arc<Mutex<MergeTrees>>);
+
+#[derive(Debug)]
+struct MergeTrees {
+    capacity: usize,
+    trees: HashMap<MergeKey, String>,
+    order: VecDeque<MergeKey>,
+}
+
+/// The AUR_SEEN and upstream HEAD a merge was computed for.
+///
+/// The merged tree only depends on the two commits so nothing else is needed to reuse it.
+type MergeKey = (String, String);
+
+impl Default for MergeCache {
+    fn default() -> Self {
+        Self::with_capacity(256)
+    }
+}
+
+impl MergeCache {
+    /// Creates a cache that keeps at most `capacity` trees, forgetting the oldest first.
+    pub fn with_capacity(capacity: usize) -> Self {
+        MergeCache(Arc::new(Mutex::new(MergeTrees {
+            capacity,
+            trees: HashMap::new(),
+            order: VecDeque::new(),
+        })))
+    }
+
+    fn get(&self, key: &MergeKey) -> Option<String> {
+        self.0.lock().unwrap().trees.get(key).cloned()
+    }
+
+    fn insert(&self, key: MergeKey, tree: String) {
+        let mut cache = self.0.lock().unwrap();
+        if cache.capacity == 0 {
+            return;
+        }
+        if cache.trees.insert(key.clone(), tree).is_some() {
+            return;
+        }
+
+        cache.order.push_back(key);
+        while cache.order.len() > cache.capacity {
+            if let Some(old) = cache.order.pop_front() {
+                cache.trees.remove(&old);
+            }
+        }
+    }
+
+    fn remove(&self, key: &MergeKey) {
+        let mut cache = self.0.lock().unwrap();
+        if cache.trees.remove(key).is_some() {
+            cache.order.retain(|k| k != key);
+        }
+    }
+}
+
+/// The flags passed to each git command along with how long it may run for.
+#[derive(Clone, Debug)]
+struct GitFlags {
+    args: Vec<String>,
+    env: Vec<(String, OsString)>,
+    timeout: Option<Duration>,
+    cancel: Option<CancelToken>,
+}
+
+impl GitFlags {
+    /// Sets the extra environment variables commands are run with.
+    fn set_env(&self, command: &mut Command) {
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+    }
+
+    /// Spawns commands that may be killed in their own process group so that any processes
+    /// they start are killed along with them.
+    fn own_group(&self, command: &mut Command) {
+        if self.timeout.is_some() || self.cancel.is_some() {
+            command.process_group(0);
+        }
+    }
+}
+
+impl Deref for GitFlags {
+    type Target = Vec<String>;
+
+    fn deref(&self) -> &Vec<String> {
+        &self.args
+    }
+}
+
+impl DerefMut for GitFlags {
+    fn deref_mut(&mut self) -> &mut Vec<String> {
+        &mut self.args
+    }
+}
+
+pub(crate) fn timeout_err(cmd: &Command) -> Error {
+    Error::Timeout {
+        command: cmd.get_program().to_owned().into(),
+        args: cmd
+            .get_args()
+            .map(|s| s.to_string_lossy().into_owned())
+            .collect(),
+    }
+}
+
+/// Waits for a child to exit and collects its output, killing it if it runs for longer than
+/// `timeout` or `cancel` is cancelled.
+fn wait_output(
+    command: &Command,
+    mut child: Child,
+    timeout: Option<Duration>,
+    cancel: Option<&CancelToken>,
+) -> Result<Output> {
+    if timeout.is_none() && cancel.is_none() {
+        return child
+            .wait_with_output()
+            .map_err(|e| command_err(command, Some(e.to_string())));
+    }
+
+    // The pipes are read on their own threads so the child never blocks on a full pipe. They are
+    // not joined on timeout or cancellation as processes spawned by the child may still hold the pipes open.
+    let read = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buf)?;
+            }
+            Ok::<_, io::Error>(buf)
+        })
+    };
+    let stdout = read(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let status = wait_child(command, &mut child, deadline, cancel)?;
+
+    let join = |handle: thread::JoinHandle<io::Result<Vec<u8>>>| {
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("failed to read command output")))
+            .map_err(|e| command_err(command, Some(e.to_string())))
+    };
+
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Waits for a child to exit, killing it if it is still running at `deadline` or once `cancel`
+/// is cancelled.
+fn wait_child(
+    command: &Command,
+    child: &mut Child,
+    deadline: Option<Instant>,
+    cancel: Option<&CancelToken>,
+) -> Result<ExitStatus> {
+    if deadline.is_none() && cancel.is_none() {
+        return child
+            .wait()
+            .map_err(|e| command_err(command, Some(e.to_string())));
+    }
+
+    loop {
+        let status = match child.try_wait() {
+            Ok(status) => status,
+            Err(e) => {
+                kill_child(child);
+                return Err(command_err(command, Some(e.to_string())));
+            }
+        };
+        if let Some(status) = status {
+            return Ok(status);
+        }
+        if cancel.is_some_and(|cancel| cancel.is_cancelled()) {
+            kill_child(child);
+            return Err(Error::Cancelled {
+                fetched: Vec::new(),
+            });
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill_child(child);
+            return Err(timeout_err(command));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Kills a child along with the processes it started, such as git's remote helpers.
+///
+/// The processes are only found when the child was spawned in its own process group.
+pub(crate) fn kill_child(child: &mut Child) {
+    kill_group(child.id());
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Kills the process group led by `pid`.
+///
+/// Does nothing if `pid` does not lead a group. As the process has not been reaped its pid can
+/// not have been reused for another group.
+pub(crate) fn kill_group(pid: u32) {
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        // SAFETY: kill has no memory safety requirements.
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+}
+
+pub(crate) fn command_err(cmd: &Command, stderr: Option<String>) -> Error {
+    Error::CommandFailed(CommandFailed {
+        dir: cmd.get_current_dir().unwrap().to_owned(),
+        command: cmd.get_program().to_owned().into(),
+        args: cmd
+            .get_args()
+            .map(|s| s.to_string_lossy().into_owned())
+            .collect(),
+        stderr,
+    })
+}
+
+impl Fetch {
+    /// Create a new Handle with working defaults.
+    ///
+    /// This Inializes the clone and diff dir to the current dirrectory. If you want to configure
+    /// a cache directory you will need to do that yourself.
+    pub fn new() -> Result<Self> {
+        Ok(Self::with_dirs(env::current_dir()?, env::current_dir()?))
+    }
+
+    /// Create a new handle with a specified cache dir.
+    ///
+    /// clone_dir will be a subdirectory named clone inside of the specified path.
+    /// diff_dir will be a subdirectory named diff inside of the specified path.
+    pub fn with_cache_dir<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        Self::with_dirs(path.join("clone"), path.join("diff"))
+    }
+
+    /// Create a new handle with a specified cache dir.
+    ///
+    ///Both diffs and cloned packages will be places in the provided dir.
+    pub fn with_combined_cache_dir<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        Self::with_dirs(path.into(), path.into())
+    }
+
+    /// Create a new handle using the user's cache directory.
+    ///
+    /// This is the same as [`with_cache_dir`](fn.with_cache_dir.html) using `$XDG_CACHE_HOME/aur`
+    /// or `~/.cache/aur` if XDG_CACHE_HOME is not set.
+    pub fn with_xdg_cache() -> Result<Self> {
+        let cache = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "neither XDG_CACHE_HOME or HOME are set",
+                )
+            })?;
+
+        Ok(Self::with_cache_dir(cache.join("aur")))
+    }
+
+    /// Create a new handle with working defaults that downloads from the given mirror.
+    ///
+    /// This is the same as [`new`](fn.new.html) but with aur_url set to the mirror's URL.
+    pub fn with_mirror(mirror: Mirror) -> Result<Self> {
+        let mut fetch = Self::new()?;
+        fetch.aur_url = mirror.url();
+        fetch.branch_per_package = mirror.branch_per_package();
+        Ok(fetch)
+    }
+
+    pub(crate) fn with_dirs(clone_dir: PathBuf, diff_dir: PathBuf) -> Self {
+        Self {
+            clone_dir,
+            diff_dir,
+            git: "git".into(),
+            git_flags: Vec::new(),
+            aur_url: Mirror::Aur.url(),
+            branch_per_package: false,
+            diff_algorithm: None,
+            find_renames: None,
+            find_copies: false,
+            diff_submodule: None,
+            max_diff_bytes: None,
+            diff_preview_lines: None,
+            diff_untracked: false,
+            diff_exclude: vec![".SRCINFO".into()],
+            srcinfo_fallback: false,
+            saved_diff_stat: true,
+            keep_diff_history: false,
+            http_headers: Vec::new(),
+            proxy: None,
+            protocol_version: None,
+            batch_timeout: None,
+            cancel: CancelToken::new(),
+            command_timeout: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            clone_jobs: 20,
+            seen_ref: "AUR_SEEN".into(),
+            diff_mode: DiffMode::Merge,
+            use_worktrees: false,
+            performance_config: false,
+            clone_into_existing: false,
+            single_branch: false,
+            clone_args: Vec::new(),
+            clean_keep: Vec::new(),
+            clone_depth: None,
+            alternates: Vec::new(),
+            prune: false,
+            verify_command: None,
+            record_provenance: false,
+            merge_autostash: false,
+            merge_jobs: 1,
+            worker_name: "aur-fetch-worker".into(),
+            worker_stack_size: None,
+            conflict_policy: ConflictPolicy::Abort,
+            unrelated_policy: UnrelatedPolicy::Error,
+            lock_policy: LockPolicy::Fail,
+            fs: Arc::new(RealFs),
+            merge_cache: MergeCache::default(),
+        }
+    }
+
+    /// Downloads a list of packages to the cache dir.
+    ///
+    /// This downloads packages from the AUR using git. New packages will be cloned, while packages
+    /// that already exist in cache will be fetched. Merging will need to be done in a separate
+    /// step.
+    ///
+    /// Each package is downloaded concurrently which givess a major speedup
+    ///
+    /// Depending on how many packages are being downloaded and connection speed this
+    /// function may take a little while to complete. See [`download_cb`](fn.download_cb.html) if
+    /// you wish track the progress of each download.
+    ///
+    /// This also filters the input list to packages that were already in cache. This filtered list
+    /// can then be passed on to [`merge`](fn.merge.html) as freshly cloned packages will
+    /// not need to be merged.
+    pub fn download<S: AsRef<str> + Send + Sync>(&self, pkgs: &[S]) -> Result<Vec<String>> {
+        self.download_cb(pkgs, |_| ())
+    }
+
+    /// The same as [`download`](fn.download.html) but calls a Callback after each download.
+    ///
+    /// The callback is called each time a package download is completed.
+    pub fn download_cb<S: AsRef<str> + Send + Sync, F: Fn(Callback)>(
+        &self,
+        pkgs: &[S],
+        f: F,
+    ) -> Result<Vec<String>> {
+        self.download_repos_cb(&self.pkg_repos(pkgs), f)
+    }
+
+    /// Downloads a list of packages and diffs each one as soon as it finishes downloading.
+    ///
+    /// Each package is diffed by the same thread that downloaded it, while the rest of the
+    /// packages are still downloading. Each package is returned with its diff as from
+    /// [`diff`](fn.diff.html), or `None` if it has already been seen at the upstream HEAD.
+    /// Freshly cloned packages are diffed against an empty tree.
+    ///
+    /// Like [`download_all`](fn.download_all.html) a package failing to download or diff does
+    /// not stop the rest of the batch, instead its error is returned in its result. Results are
+    /// in the order the downloads completed, not the order of `pkgs`.
+    pub fn download_and_diff<S: AsRef<str> + Send + Sync, C: Into<Color>>(
+        &self,
+        pkgs: &[S],
+        color: C,
+    ) -> Result<Vec<(String, Result<Option<String>>)>> {
+        let color = color.into();
+        let mut ret = Vec::with_capacity(pkgs.len());
+        let mut fetched = Vec::new();
+
+        let diff = |pkg: &str, _: &PkgDownload| {
+            let path = self.pkg_dir(pkg)?;
+            if git_unseen(&self.git, &self.flags(), path, &self.seen_ref)? {
+                Ok(self.diff(&[pkg], color)?.pop())
+            } else {
+                Ok(None)
+            }
+        };
+        let timings = self.download_pool_then(
+            &self.pkg_repos(pkgs),
+            &|_| true,
+            true,
+            &diff,
+            |pkg, download| {
+                let diff = download.and_then(|(download, diff)| {
+                    if download.fetched {
+                        fetched.push(pkg.to_string());
+                    }
+                    diff
+                });
+                ret.push((pkg.to_string(), diff));
+                Ok(())
+            },
+        )?;
+
+        if timings.stopped() {
+            return Err(timings.stopped_err(fetched));
+        }
+
+        Ok(ret)
+    }
+
+    /// The same as [`download_cb`](fn.download_cb.html) but starts the packages in `priority`
+    /// before the rest.
+    ///
+    /// Packages are started in the order they appear in `priority` followed by the remaining
+    /// packages in input order. This lets a tool get the packages the user is waiting on first.
+    /// Packages in `priority` that are not in `pkgs` are ignored.
+    pub fn download_prioritized<S, P, F>(
+        &self,
+        pkgs: &[S],
+        priority: &[P],
+        f: F,
+    ) -> Result<Vec<String>>
+    where
+        S: AsRef<str> + Send + Sync,
+        P: AsRef<str>,
+        F: Fn(Callback),
+    {
+        let mut repos = self.pkg_repos(pkgs);
+        repos.sort_by_key(|repo| {
+            priority
+                .iter()
+                .position(|p| p.as_ref() == repo.name)
+                .unwrap_or(usize::MAX)
+        });
+        self.download_repos_cb(&repos, f)
+    }
+
+    /// The same as [`download`](fn.download.html) but also returns how long the batch took.
+    ///
+    /// This is intended to help diagnose slow downloads. See [`BatchTimings`] for what is measured.
+    pub fn download_timed<S: AsRef<str> + Send + Sync>(
+        &self,
+        pkgs: &[S],
+    ) -> Result<(Vec<String>, BatchTimings)> {
+        self.download_repos_timed(&self.pkg_repos(pkgs), &|_| true, |_| ())
+    }
+
+    /// The same as [`download`](fn.download.html) but returns a result for every package, not
+    /// just the ones that were fetched.
+    ///
+    /// Results are in the order the downloads completed, not the order of `pkgs`.
+    pub fn download_detailed<S: AsRef<str> + Send + Sync>(
+        &self,
+        pkgs: &[S],
+    ) -> Result<Vec<DownloadResult>> {
+        let mut results = Vec::with_capacity(pkgs.len());
+        let timings =
+            self.download_pool(&self.pkg_repos(pkgs), &|_| true, false, |pkg, download| {
+                results.push(DownloadResult::new(pkg, &download?));
+                Ok(())
+            })?;
+
+        if timings.stopped() {
+            let fetched = results
+                .into_iter()
+                .filter(|res| res.action == CloneOrFetch::Fetched)
+                .map(|res| res.name)
+                .collect();
+            return Err(timings.stopped_err(fetched));
+        }
+
+        Ok(results)
+    }
+
+    /// The same as [`download`](fn.download.html) but skips packages that do not match a filter.
+    ///
+    /// The filter is called from the download threads right before each package would be
+    /// downloaded, so expensive checks are run concurrently with the downloads. Packages the
+    /// filter returns false for are neither downloaded nor passed to the callback.
+    pub fn download_filtered<S, P, F>(&self, pkgs: &[S], filter: P, f: F) -> Result<Vec<String>>
+    where
+        S: AsRef<str> + Send + Sync,
+        P: Fn(&str) -> bool + Sync,
+        F: Fn(Callback),
+    {
+        self.download_repos_timed(&self.pkg_repos(pkgs), &filter, f)
+            .map(|(fetched, _)| fetched)
+    }
+
+    /// The same as [`download`](fn.download.html) but records progress to a manifest file so an
+    /// interrupted batch can be resumed.
+    ///
+    /// Each package is written to the manifest as soon as it finishes downloading. Packages
+    /// already listed in the manifest are skipped, so calling this again with the same manifest
+    /// after an interruption only downloads the remaining packages. The returned list includes
+    /// fetched packages from previous runs that were recorded in the manifest.
+    ///
+    /// The manifest is a plain text file where each line is `fetched <pkg>` or `cloned <pkg>`.
+    /// Delete it once the batch is complete to download the packages again.
+    pub fn download_with_manifest<S: AsRef<str> + Send + Sync, P: AsRef<Path>>(
+        &self,
+        pkgs: &[S],
+        manifest: P,
+    ) -> Result<Vec<String>> {
+        let manifest = manifest.as_ref();
+        let mut done = HashMap::new();
+
+        match self.fs.read(manifest) {
+            Ok(s) => {
+                for line in String::from_utf8_lossy(&s).lines() {
+                    if let Some((action, pkg)) = line.split_once(' ') {
+                        done.insert(pkg.to_string(), action == "fetched");
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut fetched = pkgs
+            .iter()
+            .map(|p| p.as_ref())
+            .filter(|p| done.get(*p) == Some(&true))
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        let todo = pkgs
+            .iter()
+            .map(|p| p.as_ref())
+            .filter(|p| !done.contains_key(*p))
+            .collect::<Vec<_>>();
+        // download_pkg fetches packages that are already cloned and clones the rest.
+        let already_cloned = todo
+            .iter()
+            .filter(|p| self.is_git_repo(p))
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+
+        // Fail before downloading anything if the manifest can not be written to.
+        self.fs.append(manifest, b"")?;
+        let write_err = RefCell::new(None);
+
+        fetched.extend(self.download_cb(&todo, |cb| {
+            let action = if already_cloned.iter().any(|p| p == cb.pkg) {
+                "fetched"
+            } else {
+                "cloned"
+            };
+            let line = format!("{} {}\n", action, cb.pkg);
+            let res = self.fs.append(manifest, line.as_bytes());
+            if let Err(e) = res {
+                write_err.borrow_mut().get_or_insert(e);
+            }
+        })?);
+
+        if let Some(e) = write_err.into_inner() {
+            return Err(e.into());
+        }
+
+        Ok(fetched)
+    }
+
+    /// The same as [`download_cb`](fn.download_cb.html) but carries on when a package fails to
+    /// download.
+    ///
+    /// The callback is only called for packages that downloaded successfully.
+    pub fn download_cb_partial<S: AsRef<str> + Send + Sync, F: Fn(Callback)>(
+        &self,
+        pkgs: &[S],
+        f: F,
+    ) -> Result<PartialDownload> {
+        let mut fetched = Vec::with_capacity(pkgs.len());
+        let mut failed = Vec::new();
+        let mut n = 0;
+
+        let timings =
+            self.download_pool(&self.pkg_repos(pkgs), &|_| true, true, |pkg, download| {
+                let download = match download {
+                    Ok(download) => download,
+                    Err(e) => {
+                        failed.push((pkg.to_string(), e));
+                        return Ok(());
+                    }
+                };
+                n += 1;
+                f(Callback {
+                    pkg,
+                    n,
+                    output: String::from_utf8_lossy(&download.output).trim(),
+                });
+                if download.fetched {
+                    fetched.push(pkg.to_string());
+                }
+                Ok(())
+            })?;
+
+        if timings.stopped() {
+            return Err(timings.stopped_err(fetched));
+        }
+
+        Ok(PartialDownload { fetched, failed })
+    }
+
+    /// Downloads a list of packages, carrying on when a package fails to download.
+    ///
+    /// Unlike [`download`](fn.download.html) a failure does not stop the rest of the batch.
+    /// Instead the result of each package is returned along with its name. Results are in the
+    /// order the downloads completed, not the order of `pkgs`.
+    pub fn download_all<S: AsRef<str> + Send + Sync>(
+        &self,
+        pkgs: &[S],
+    ) -> Result<Vec<(String, Result<DownloadResult>)>> {
+        let mut results = Vec::with_capacity(pkgs.len());
+        let timings =
+            self.download_pool(&self.pkg_repos(pkgs), &|_| true, true, |pkg, download| {
+                let res = download.map(|download| DownloadResult::new(pkg, &download));
+                results.push((pkg.to_string(), res));
+                Ok(())
+            })?;
+
+        if timings.stopped() {
+            let fetched = results
+                .into_iter()
+                .filter_map(|(_, res)| res.ok())
+                .filter(|res| res.action == CloneOrFetch::Fetched)
+                .map(|res| res.name)
+                .collect();
+            return Err(timings.stopped_err(fetched));
+        }
+
+        Ok(results)
+    }
+
+    /// Downloads the packages that failed in the results of
+    /// [`download_all`](fn.download_all.html) again.
+    ///
+    /// Only the failed packages are downloaded and returned.
+    pub fn retry_failed(
+        &self,
+        results: &[(String, Result<DownloadResult>)],
+    ) -> Result<Vec<(String, Result<DownloadResult>)>> {
+        let failed = results
+            .iter()
+            .filter(|(_, res)| res.is_err())
+            .map(|(pkg, _)| pkg.as_str())
+            .collect::<Vec<_>>();
+        self.download_all(&failed)
+    }
+
+    /// The same as [`download`](fn.download.html) but downloads a specified list of repos instead of AUR packages.
+    ///
+    /// Repos are cloned into a directory named after them. Repeated repos are only downloaded
+    /// once but repos with the same name and different URLs are an error.
+    pub fn download_repos<F: Fn(Callback)>(&self, repos: &[Repo]) -> Result<Vec<String>> {
+        self.download_repos_cb(repos, |_| ())
+    }
+
+    /// The same as [`download_repos`](fn.download_repos.html) but calls a Callback after each download.
+    ///
+    /// The callback is called each time a package download is completed.
+    pub fn download_repos_cb<F: Fn(Callback)>(&self, repos: &[Repo], f: F) -> Result<Vec<String>> {
+        self.download_repos_timed(repos, &|_| true, f)
+            .map(|(fetched, _)| fetched)
+    }
+
+    fn download_repos_timed<F: Fn(Callback)>(
+        &self,
+        repos: &[Repo],
+        filter: &(dyn Fn(&str) -> bool + Sync),
+        f: F,
+    ) -> Result<(Vec<String>, BatchTimings)> {
+        let mut fetched = Vec::with_capacity(repos.len());
+        let mut n = 0;
+
+        let timings = self.download_pool(repos, filter, false, |pkg, download| {
+            let download = download?;
+            n += 1;
+            f(Callback {
+                pkg,
+                n,
+                output: String::from_utf8_lossy(&download.output).trim(),
+            });
+            if download.fetched {
+                fetched.push(pkg.to_string())
+            }
+            Ok(())
+        })?;
+
+        if timings.stopped() {
+            return Err(timings.stopped_err(fetched));
+        }
+
+        Ok((fetched, timings))
+    }
+
+    /// Downloads repos concurrently, calling `on_done` from the current thread as each finishes.
+    ///
+    /// If `keep_going` is false the first failed download stops any further downloads from
+    /// starting. Returning an error from `on_done` also stops the batch and returns that error.
+    ///
+    /// If the batch timeout is reached or the batch is cancelled no further downloads are started
+    /// and timed_out or cancelled is set in the returned timings.
+    fn download_pool<F>(
+        &self,
+        repos: &[Repo],
+        filter: &(dyn Fn(&str) -> bool + Sync),
+        keep_going: bool,
+        mut on_done: F,
+    ) -> Result<BatchTimings>
+    where
+        F: FnMut(&str, Result<PkgDownload>) -> Result<()>,
+    {
+        self.download_pool_then(repos, filter, keep_going, &|_, _| (), |pkg, download| {
+            on_done(pkg, download.map(|(download, ())| download))
+        })
+    }
+
+    /// The same as [`download_pool`] but also runs `then` on the worker after each package is
+    /// downloaded, passing its result to `on_done` along with the download.
+    fn download_pool_then<T, F>(
+        &self,
+        repos: &[Repo],
+        filter: &(dyn Fn(&str) -> bool + Sync),
+        keep_going: bool,
+        then: &(dyn Fn(&str, &PkgDownload) -> T + Sync),
+        mut on_done: F,
+    ) -> Result<BatchTimings>
+    where
+        T: Send,
+        F: FnMut(&str, Result<(PkgDownload, T)>) -> Result<()>,
+    {
+        repos
+            .iter()
+            .try_for_each(|repo| validate_name(&repo.name))?;
+        let repos = &unique_repos(repos)?;
+        self.warn_case_collisions(repos);
+
+        let start = Instant::now();
+        let mut timings = BatchTimings::default();
+        let (pkg_send, pkg_rec) = channel::bounded(0);
+        let (fetched_send, fetched_rec) = channel::bounded(32);
+        let stop = &AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for &repo in repos {
+                    if pkg_send.send(repo).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            for n in 0..self.clone_jobs.max(1).min(repos.len()) {
+                let fetched_send = fetched_send.clone();
+                let pkg_rec = pkg_rec.clone();
+                self.worker(n)
+                    .spawn_scoped(scope, move || {
+                        for repo in &pkg_rec {
+                            if stop.load(Ordering::Acquire) || self.cancel.is_cancelled() {
+                                break;
+                            }
+                            if !filter(&repo.name) {
+                                continue;
+                            }
+                            let download = self.download_pkg(repo).map(|download| {
+                                let res = then(&repo.name, &download);
+                                (download, res)
+                            });
+                            let failed = download.is_err();
+                            if failed && !keep_going {
+                                stop.store(true, Ordering::Release);
+                            }
+                            let _ = fetched_send.send((repo.name.clone(), download));
+                            if failed && !keep_going {
+                                break;
+                            }
+                        }
+                    })
+                    .expect("failed to spawn worker thread");
+            }
+
+            drop(pkg_rec);
+            drop(fetched_send);
+
+            let deadline = self.batch_timeout.map(|timeout| start + timeout);
+
+            let res = loop {
+                if self.cancel.is_cancelled() {
+                    stop.store(true, Ordering::Release);
+                    timings.cancelled = true;
+                    break Ok(());
+                }
+                // Checked here as well as messages that are already queued are received even
+                // once the deadline has passed.
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    stop.store(true, Ordering::Release);
+                    timings.timed_out = true;
+                    break Ok(());
+                }
+
+                // Wake up periodically to notice cancellation.
+                let poll = Instant::now() + CANCEL_POLL;
+                let wait = deadline.map_or(poll, |deadline| deadline.min(poll));
+
+                let (pkg, download) = match fetched_rec.recv_deadline(wait) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) if deadline == Some(wait) => {
+                        stop.store(true, Ordering::Release);
+                        timings.timed_out = true;
+                        break Ok(());
+                    }
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break Ok(()),
+                };
+
+                if let Ok((download, _)) = &download {
+                    timings.packages += 1;
+                    timings.spawn += download.spawn;
+                    timings.fetch += download.fetch;
+                    timings.checkout += download.checkout;
+                }
+                if let Err(e) = on_done(&pkg, download) {
+                    stop.store(true, Ordering::Release);
+                    break Err(e);
+                }
+            };
+
+            // Workers may be blocked sending to the full channel. Dropping the receiver makes
+            // their sends fail so they can see the stop flag and exit.
+            drop(fetched_rec);
+            res?;
+
+            timings.total = start.elapsed();
+            Ok(timings)
+        })
+    }
+
+    pub(crate) fn warn_case_collisions(&self, repos: &[&Repo]) {
+        let mut seen = HashMap::new();
+        let collisions = repos
+            .iter()
+            .filter_map(|repo| {
+                seen.insert(repo.name.to_lowercase(), &repo.name)
+                    .filter(|&other| *other != repo.name)
+                    .map(|other| (other, &repo.name))
+            })
+            .collect::<Vec<_>>();
+
+        if collisions.is_empty() || !matches!(self.case_insensitive(), Ok(true)) {
+            return;
+        }
+
+        for (a, b) in collisions {
+            log::warn!(
+                "{} and {} share a clone dir on a case-insensitive filesystem",
+                a,
+                b
+            );
+        }
+    }
+
+    pub(crate) fn pkg_repos<S: AsRef<str>>(&self, pkgs: &[S]) -> Vec<Repo> {
+        pkgs.iter()
+            .map(|p| Repo {
+                url: self.pkg_url(p.as_ref()),
+                name: p.as_ref().to_string(),
+                reference: None,
+                branch: self.branch_per_package.then(|| p.as_ref().to_string()),
+            })
+            .collect()
+    }
+
+    fn pkg_url(&self, pkg: &str) -> Url {
+        if self.branch_per_package {
+            return self.aur_url.clone();
+        }
+        let mut url = self.aur_url.clone();
+        let path = format!("{}/{}", url.path().trim_end_matches('/'), pkg);
+        url.set_path(&path);
+        url
+    }
+
+    fn download_pkg(&self, repo: &Repo) -> Result<PkgDownload> {
+        self.mk_clone_dir()?;
+
+        if self.clones_into_existing(&repo.name) {
+            return self.download_existing(repo);
+        }
+
+        let (mut command, fetched, guard) = self.download_command(repo)?;
+        log_cmd(&command);
+        let mut spawn = Duration::ZERO;
+        let mut fetch = Duration::ZERO;
+        let mut attempt = 0;
+
+        let output = loop {
+            let start = Instant::now();
+            let child = command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e| command_err(&command, Some(e.to_string())))?;
+            let spawned = start.elapsed();
+            let output = wait_output(&command, child, self.command_timeout, Some(&self.cancel));
+            spawn += spawned;
+            fetch += start.elapsed() - spawned;
+
+            if !self.should_retry(&repo.name, &output, &mut attempt) {
+                break output?;
+            }
+            guard.reset()?;
+            thread::sleep(self.retry_delay);
+        };
+
+        let start = Instant::now();
+        let output = self.finish_download(repo, &command, output, guard, fetched)?;
+        Ok(PkgDownload {
+            fetched,
+            output,
+            spawn,
+            fetch,
+            checkout: start.elapsed(),
+        })
+    }
+
+    pub(crate) fn clones_into_existing(&self, dir: &str) -> bool {
+        !self.is_git_repo(dir)
+            && self.clone_into_existing
+            && self.pkg_dir(dir).is_ok_and(|dir| dir.is_dir())
+    }
+
+    pub(crate) fn download_existing(&self, repo: &Repo) -> Result<PkgDownload> {
+        let start = Instant::now();
+        let (output, spawn, fetch) = self.clone_existing(repo)?;
+        self.checkout_reference(repo)?;
+        self.record_provenance(repo)?;
+        Ok(PkgDownload {
+            fetched: false,
+            output,
+            spawn,
+            fetch,
+            checkout: start.elapsed() - spawn - fetch,
+        })
+    }
+
+    /// Builds the clone or fetch command for a repo.
+    ///
+    /// Also returns whether the repo is being fetched and a guard that removes a partial clone
+    /// unless the download is finished with `finish_download`. A repo being fetched has its
+    /// remote pointed at the repo's URL first.
+    pub(crate) fn download_command(&self, repo: &Repo) -> Result<(Command, bool, PartialClone)> {
+        let url = &repo.url;
+        let dir = repo.name.as_str();
+
+        let mut command = Command::new(&self.git);
+        command.args(self.download_config());
+        command.envs(self.download_env());
+        if !self.alternates.is_empty() {
+            let alternates = env::join_paths(&self.alternates)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            command.env("GIT_ALTERNATE_OBJECT_DIRECTORIES", alternates);
+        }
+        // So that git's remote helpers are killed along with it on timeout or cancellation.
+        command.process_group(0);
+        let mut guard = PartialClone(None);
+
+        let fetched = if self.is_git_repo(dir) {
+            self.set_remote_url(repo)?;
+            command.current_dir(self.pkg_dir(dir)?);
+            command.args(["fetch", "-v"]);
+            if let Some(depth) = self.clone_depth {
+                command.arg(format!("--depth={}", depth));
+            }
+            if self.prune {
+                command.arg("--prune");
+            }
+            true
+        } else {
+            let path = self.pkg_dir(dir)?;
+            if !path.exists() {
+                guard = PartialClone(Some(path));
+            }
+            command.current_dir(&self.clone_dir);
+            command.args(["clone", "--no-progress"]);
+            if let Some(branch) = &repo.branch {
+                command.args(["--single-branch", "--branch", branch]);
+            } else if self.single_branch {
+                command.arg("--single-branch");
+            }
+            if let Some(depth) = self.clone_depth {
+                command.arg(format!("--depth={}", depth));
+            }
+            command.args(&self.clone_args);
+            command.args(["--", url.as_str(), dir]);
+            false
+        };
+
+        Ok((command, fetched, guard))
+    }
+
+    /// Points origin at the repo's URL if it has changed, such as after switching mirrors.
+    ///
+    /// The fetched branches and the branch master tracks are switched along with it as the
+    /// mirrors may not share a layout.
+    fn set_remote_url(&self, repo: &Repo) -> Result<()> {
+        let path = self.pkg_dir(&repo.name)?;
+        let flags = self.flags();
+
+        if self.origin_url(&repo.name)?.as_ref() == Some(&repo.url) {
+            return Ok(());
+        }
+
+        git_command(
+            &self.git,
+            &path,
+            &flags,
+            &["remote", "set-url", "origin", repo.url.as_str()],
+        )?;
+        let branch = repo.branch.as_deref().unwrap_or("master");
+        let fetched = if repo.branch.is_some() { branch } else { "*" };
+        git_command(
+            &self.git,
+            &path,
+            &flags,
+            &["remote", "set-branches", "origin", fetched],
+        )?;
+
+        let remote = git_command_allow(
+            &self.git,
+            &path,
+            &flags,
+            &["config", "branch.master.remote"],
+            &[1],
+        )?;
+        // Pinned repos track a local ref instead.
+        if String::from_utf8_lossy(&remote.stdout).trim() == "origin" {
+            let merge = format!("refs/heads/{}", branch);
+            git_command(
+                &self.git,
+                &path,
+                &flags,
+                &["config", "branch.master.merge", &merge],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a failed download should be tried again, counting the attempt if so.
+    pub(crate) fn should_retry(
+        &self,
+        pkg: &str,
+        output: &Result<Output>,
+        attempt: &mut u32,
+    ) -> bool {
+        let retry = match output {
+            Ok(output) => !output.status.success() && is_network_error(&output.stderr),
+            Err(Error::Timeout { .. }) => true,
+            Err(_) => false,
+        };
+        if !retry || *attempt >= self.retries || self.cancel.is_cancelled() {
+            return false;
+        }
+
+        *attempt += 1;
+        log::warn!(
+            "downloading {} failed, retrying ({}/{})",
+            pkg,
+            attempt,
+            self.retries
+        );
+        true
+    }
+
+    /// Checks the download succeeded and does the rest of the setup, returning git's output.
+    pub(crate) fn finish_download(
+        &self,
+        repo: &Repo,
+        command: &Command,
+        output: Output,
+        guard: PartialClone,
+        fetched: bool,
+    ) -> Result<Vec<u8>> {
+        if !output.status.success() {
+            return Err(command_err(
+                command,
+                Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+            ));
+        }
+
+        self.write_alternates(&repo.name)?;
+        if !fetched && repo.branch.is_some() {
+            // Name the branch like every other package's.
+            git_command(
+                &self.git,
+                self.pkg_dir(&repo.name)?,
+                &self.flags(),
+                &["branch", "-q", "-m", "master"],
+            )?;
+        }
+        guard.finish();
+        self.checkout_reference(repo)?;
+        self.record_provenance(repo)?;
+        Ok(output.stderr)
+    }
+
+    /// Adds the configured alternates to the package's repo.
+    ///
+    /// Objects found in the alternates are not downloaded so the repo needs to keep using them
+    /// after the download.
+    fn write_alternates(&self, pkg: &str) -> Result<()> {
+        if self.alternates.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.pkg_dir(pkg)?;
+        let file = git_command(
+            &self.git,
+            &path,
+            &self.flags(),
+            &["rev-parse", "--git-path", "objects/info/alternates"],
+        )?;
+        let file = path.join(String::from_utf8_lossy(&file.stdout).trim());
+
+        let mut contents = match read_to_string(&file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let len = contents.len();
+
+        for alternate in &self.alternates {
+            let alternate = alternate.to_string_lossy();
+            if !contents.lines().any(|line| line == alternate) {
+                contents.push_str(&alternate);
+                contents.push('\n');
+            }
+        }
+
+        if contents.len() != len {
+            fs::write(&file, contents)?;
+        }
+
+        Ok(())
+    }
+
+    fn record_provenance(&self, repo: &Repo) -> Result<()> {
+        if !self.record_provenance {
+            return Ok(());
+        }
+
+        let pkg = repo.name.as_str();
+        let provenance = Provenance {
+            url: repo.url.to_string(),
+            time: SystemTime::now(),
+            commit: self.upstream_head(pkg)?,
+        };
+        self.mk_diff_dir()?;
+        self.fs
+            .write(&self.provenance_path(pkg)?, provenance.to_json().as_bytes())?;
+        Ok(())
+    }
+
+    /// Fetches the repo's reference, if it has one, and makes the checked out branch track it.
+    ///
+    /// The branch tracks [`PINNED_REF`] in place of origin so that `HEAD@{u}` is the pinned
+    /// commit. Repos that are no longer pinned go back to tracking origin.
+    fn checkout_reference(&self, repo: &Repo) -> Result<()> {
+        let path = self.pkg_dir(&repo.name)?;
+        let flags = self.flags();
+        let upstream = git_command_allow(
+            &self.git,
+            &path,
+            &flags,
+            &["rev-parse", "--symbolic-full-name", "@{u}"],
+            &[128],
+        )?;
+        let pinned = String::from_utf8_lossy(&upstream.stdout).trim() == PINNED_REF;
+        if repo.reference.is_none() && !pinned {
+            return Ok(());
+        }
+
+        let branch = git_command(
+            &self.git,
+            &path,
+            &flags,
+            &["symbolic-ref", "--short", "HEAD"],
+        )?;
+        let branch = String::from_utf8_lossy(&branch.stdout).trim().to_string();
+
+        let reference = match &repo.reference {
+            Some(reference) => reference,
+            None => {
+                let origin = format!("origin/{}", repo.branch.as_deref().unwrap_or(&branch));
+                git_command(
+                    &self.git,
+                    &path,
+                    &flags,
+                    &["branch", "-q", "--set-upstream-to", &origin],
+                )?;
+                return Ok(());
+            }
+        };
+
+        let fetch_flags = self.download_flags();
+        git_command(
+            &self.git,
+            &path,
+            &fetch_flags,
+            &["fetch", "-q", "origin", reference],
+        )?;
+        git_command(
+            &self.git,
+            &path,
+            &flags,
+            &["update-ref", PINNED_REF, "FETCH_HEAD^{commit}"],
+        )?;
+
+        if !pinned {
+            // The branch is still at the default branch's HEAD, which may be past the pin.
+            git_command(
+                &self.git,
+                &path,
+                &flags,
+                &["checkout", "-q", "-B", &branch, PINNED_REF],
+            )?;
+            let remote = format!("branch.{}.remote", branch);
+            let merge = format!("branch.{}.merge", branch);
+            git_command(&self.git, &path, &flags, &["config", &remote, "."])?;
+            git_command(&self.git, &path, &flags, &["config", &merge, PINNED_REF])?;
+        }
+
+        Ok(())
+    }
+
+    /// The path a package is cloned to.
+    ///
+    /// Names that could refer to a path outside of the cache are refused with
+    /// [`Error::InvalidPackageName`](enum.Error.html#variant.InvalidPackageName).
+    pub fn pkg_dir<S: AsRef<str>>(&self, pkg: S) -> Result<PathBuf> {
+        validate_name(pkg.as_ref())?;
+        Ok(self.clone_dir.join(pkg.as_ref()))
+    }
+
+    /// The path a package's diff is saved to by [`save_diffs`](fn.save_diffs.html).
+    ///
+    /// Names are checked as with [`pkg_dir`](fn.pkg_dir.html).
+    pub fn diff_path<S: AsRef<str>>(&self, pkg: S) -> Result<PathBuf> {
+        validate_name(pkg.as_ref())?;
+        Ok(self.diff_dir.join(format!("{}.diff", pkg.as_ref())))
+    }
+
+    fn provenance_path(&self, pkg: &str) -> Result<PathBuf> {
+        validate_name(pkg)?;
+        Ok(self.diff_dir.join(format!("{}.aur-fetch.json", pkg)))
+    }
+
+    /// Clones into a directory that already exists and may already contain files.
+    ///
+    /// Also returns the time spent spawning and waiting for the fetch.
+    fn clone_existing(&self, repo: &Repo) -> Result<(Vec<u8>, Duration, Duration)> {
+        let dir = repo.name.as_str();
+        let path = self.pkg_dir(dir)?;
+        let flags = self.flags();
+        let fetch_flags = self.download_flags();
+
+        git_command(&self.git, &path, &flags, &["init", "-q"])?;
+
+        let res = (|| {
+            self.write_alternates(dir)?;
+            let mut remote_add = vec!["remote", "add"];
+            if let Some(branch) = &repo.branch {
+                remote_add.extend(["-t", branch]);
+            }
+            remote_add.extend(["origin", repo.url.as_str()]);
+            git_command(&self.git, &path, &flags, &remote_add)?;
+            let start = Instant::now();
+            let (output, spawn) = git_command_with(
+                &self.git,
+                &path,
+                &fetch_flags,
+                &["fetch", "-v", "origin"],
+                &[],
+            )?;
+            let fetch = start.elapsed() - spawn;
+            let (branch, head) = match &repo.branch {
+                Some(branch) => ("master".to_string(), format!("origin/{}", branch)),
+                None => {
+                    git_command(
+                        &self.git,
+                        &path,
+                        &fetch_flags,
+                        &["remote", "set-head", "origin", "--auto"],
+                    )?;
+                    let head = git_command(
+                        &self.git,
+                        &path,
+                        &flags,
+                        &["symbolic-ref", "--short", "refs/remotes/origin/HEAD"],
+                    )?;
+                    let head = String::from_utf8_lossy(&head.stdout).trim().to_string();
+                    (head.trim_start_matches("origin/").to_string(), head)
+                }
+            };
+            git_command(
+                &self.git,
+                &path,
+                &flags,
+                &["checkout", "-q", "-b", &branch, "--track", &head],
+            )?;
+            Ok((output.stderr, spawn, fetch))
+        })();
+
+        // Don't leave behind a half set up repo that would be fetched next time.
+        if res.is_err() {
+            let _ = remove_dir_all(path.join(".git"));
+        }
+        res
+    }
+
+    /// Filters a list of packages, keep ones that have a diff.
+    ///
+    /// A reoo has a diff if AUR_SEEN is defined and is different to the upstram HEAD.
+    pub fn has_diff<'a, S: AsRef<str>>(&self, pkgs: &'a [S]) -> Result<Vec<&'a str>> {
+        let mut ret = Vec::new();
+
+        for pkg in pkgs {
+            if git_has_diff(
+                &self.git,
+                &self.flags(),
+                self.pkg_dir(pkg.as_ref())?,
+                &self.seen_ref,
+            )? {
+                ret.push(pkg.as_ref());
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Filterrs a list of packages, keeping ones that have not yet been seen.
+    ///
+    /// A repo is seen if AUR_SEEN exists and is equal to the upstram HEAD.
+    pub fn unseen<'a, S: AsRef<str>>(&self, pkgs: &'a [S]) -> Result<Vec<&'a str>> {
+        let mut ret = Vec::new();
+
+        for pkg in pkgs {
+            if git_unseen(
+                &self.git,
+                &self.flags(),
+                self.pkg_dir(pkg.as_ref())?,
+                &self.seen_ref,
+            )? {
+                ret.push(pkg.as_ref());
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Gets the commit hash of a package's upstream HEAD.
+    pub fn upstream_head<S: AsRef<str>>(&self, pkg: S) -> Result<String> {
+        git_rev_parse(
+            &self.git,
+            &self.flags(),
+            self.pkg_dir(pkg.as_ref())?,
+            "HEAD@{u}",
+        )
+    }
+
+    /// Reads where and when a package was last downloaded.
+    ///
+    /// Returns `None` if no provenance was recorded for the package. See
+    /// [`record_provenance`](#structfield.record_provenance).
+    pub fn provenance<S: AsRef<str>>(&self, pkg: S) -> Result<Option<Provenance>> {
+        match self.fs.read(&self.provenance_path(pkg.as_ref())?) {
+            Ok(json) => Ok(Provenance::from_json(&String::from_utf8_lossy(&json))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Gets the commit date of a package's upstream HEAD.
+    ///
+    /// Returns `None` if git did not report a valid date.
+    pub fn upstream_date<S: AsRef<str>>(&self, pkg: S) -> Result<Option<SystemTime>> {
+        git_commit_time(
+            &self.git,
+            &self.flags(),
+            self.pkg_dir(pkg.as_ref())?,
+            "HEAD@{u}",
+        )
+    }
+
+    /// Computes an identifier for the reviewable state of a package.
+    ///
+    /// This is a digest of the upstream tree and the commit AUR_SEEN points to. It stays the same
+    /// as long as neither changes, so it can be used to cache review decisions. Upstream
+    /// commits that do not change any files do not change the id.
+    pub fn review_id<S: AsRef<str>>(&self, pkg: S) -> Result<String> {
+        let path = self.pkg_dir(pkg.as_ref())?;
+        let flags = self.flags();
+        let tree = git_rev_parse(&self.git, &flags, &path, "HEAD@{u}^{tree}")?;
+        let seen = if git_has_seen(&self.git, &flags, &path, &self.seen_ref)? {
+            git_rev_parse(&self.git, &flags, &path, &self.seen_ref)?
+        } else {
+            String::new()
+        };
+
+        git_hash_object(
+            &self.git,
+            &flags,
+            &path,
+            format!("tree {}\nseen {}\n", tree, seen).as_bytes(),
+        )
+    }
+
+    /// Records the upstream HEAD of a list of packages.
+    ///
+    /// The snapshot can later be passed to [`changed_since`](fn.changed_since.html) to find
+    /// which packages have been updated since it was taken.
+    pub fn snapshot<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<UpstreamSnapshot> {
+        let mut heads = HashMap::new();
+
+        for pkg in pkgs {
+            heads.insert(pkg.as_ref().to_string(), self.upstream_head(pkg)?);
+        }
+
+        Ok(UpstreamSnapshot { heads })
+    }
+
+    /// Lists the packages in a snapshot whose upstream HEAD has changed since it was taken.
+    ///
+    /// Packages need to have been downloaded again for upstream changes to be seen. The
+    /// returned list is sorted by name.
+    pub fn changed_since(&self, snapshot: &UpstreamSnapshot) -> Result<Vec<String>> {
+        let mut ret = Vec::new();
+
+        for (pkg, head) in &snapshot.heads {
+            if &self.upstream_head(pkg)? != head {
+                ret.push(pkg.clone());
+            }
+        }
+
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Lists the distinct authors of the upstream commits that have not been seen.
+    ///
+    /// Authors are formatted as `Name <email>` in the order they first appear in the log, newest
+    /// first. If the package has never been seen every upstream commit is included.
+    pub fn incoming_authors<S: AsRef<str>>(&self, pkg: S) -> Result<Vec<String>> {
+        let path = self.pkg_dir(pkg.as_ref())?;
+        let range = if git_has_seen(&self.git, &self.flags(), &path, &self.seen_ref)? {
+            format!("{}..HEAD@{{u}}", self.seen_ref)
+        } else {
+            "HEAD@{u}".to_string()
+        };
+
+        let output = git_command(
+            &self.git,
+            &path,
+            &self.flags(),
+            &["log", "--format=%an <%ae>", &range],
+        )?;
+        let mut authors: Vec<String> = Vec::new();
+
+        for author in String::from_utf8_lossy(&output.stdout).lines() {
+            if !authors.iter().any(|a| a == author) {
+                authors.push(author.to_string());
+            }
+        }
+
+        Ok(authors)
+    }
+
+    /// Counts how many commits a package is ahead and behind its upstream.
+    ///
+    /// The first count is the number of local commits not in upstream, such as commits made with
+    /// [`commit`](fn.commit.html). The second is the number of upstream commits not yet merged.
+    pub fn ahead_behind<S: AsRef<str>>(&self, pkg: S) -> Result<(usize, usize)> {
+        git_ahead_behind(&self.git, &self.flags(), self.pkg_dir(pkg.as_ref())?)
+    }
+
+    /// Counts the upstream commits that have not been seen yet.
+    ///
+    /// Commits are counted from the seen ref if the package has been seen, otherwise from the
+    /// local HEAD. Returns 0 if the package has no upstream.
+    pub fn commits_behind<S: AsRef<str>>(&self, pkg: S) -> Result<usize> {
+        let path = self.pkg_dir(pkg.as_ref())?;
+        let flags = self.flags();
+
+        if git_command(
+            &self.git,
+            &path,
+            &flags,
+            &["rev-parse", "--verify", "-q", "HEAD@{u}"],
+        )
+        .is_err()
+        {
+            return Ok(0);
+        }
+
+        let base = if git_has_seen(&self.git, &flags, &path, &self.seen_ref)? {
+            self.seen_ref.as_str()
+        } else {
+            "HEAD"
+        };
+        let range = format!("{}..HEAD@{{u}}", base);
+        let output = git_command(&self.git, &path, &flags, &["rev-list", "--count", &range])?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .unwrap_or(0))
+    }
+
+    /// The same as [`commits_behind`](fn.commits_behind.html) for a list of packages.
+    ///
+    /// The counts are in the same order as `pkgs`.
+    pub fn batch_commits_behind<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<Vec<usize>> {
+        pkgs.iter().map(|pkg| self.commits_behind(pkg)).collect()
+    }
+
+    /// Gets a summary of the state of a package.
+    ///
+    /// Local changes take priority over upstream changes. Untracked files such as build
+    /// artifacts are not counted as local changes.
+    pub fn status<S: AsRef<str>>(&self, pkg: S) -> Result<PackageStatus> {
+        let pkg = pkg.as_ref();
+        let path = self.pkg_dir(pkg)?;
+        if !self.is_git_repo(pkg) {
+            return Ok(PackageStatus::NotCloned);
+        }
+
+        let flags = self.flags();
+
+        if git_is_dirty(&self.git, &flags, &path)? {
+            return Ok(PackageStatus::LocalChanges);
+        }
+
+        let (_, behind) = git_ahead_behind(&self.git, &flags, &path)?;
+        if behind == 0 {
+            Ok(PackageStatus::UpToDate)
+        } else if git_unseen(&self.git, &flags, &path, &self.seen_ref)? {
+            Ok(PackageStatus::UpdatesAvailable { commits: behind })
+        } else {
+            Ok(PackageStatus::Reviewed)
+        }
+    }
+
+    /// Gets a summary of the state of a list of packages.
+    ///
+    /// The returned statuses are in the same order as `pkgs`. See [`status`](fn.status.html).
+    pub fn statuses<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<Vec<PackageStatus>> {
+        pkgs.iter().map(|pkg| self.status(pkg)).collect()
+    }
+
+    /// Reports the state of a list of packages.
+    ///
+    /// Packages that are not cloned are reported as such with the rest of their fields left
+    /// empty.
+    pub fn batch_status<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<Vec<StatusReport>> {
+        let mut ret = Vec::with_capacity(pkgs.len());
+
+        for pkg in pkgs {
+            let pkg = pkg.as_ref();
+            validate_name(pkg)?;
+            let mut report = StatusReport {
+                pkg: pkg.to_string(),
+                cached: self.is_git_repo(pkg),
+                has_diff: false,
+                unseen: false,
+                upstream_head: None,
+                commits_behind: 0,
+            };
+
+            if report.cached {
+                let path = self.pkg_dir(pkg)?;
+                report.has_diff = git_has_diff(&self.git, &self.flags(), &path, &self.seen_ref)?;
+                report.unseen = git_unseen(&self.git, &self.flags(), &path, &self.seen_ref)?;
+                report.upstream_head = Some(self.upstream_head(pkg)?);
+                report.commits_behind = self.commits_behind(pkg)?;
+            }
+
+            ret.push(report);
+        }
+
+        Ok(ret)
+    }
+
+    /// The same as [`batch_status`](fn.batch_status.html) but serializes the reports to a JSON
+    /// array.
+    #[cfg(feature = "serde")]
+    pub fn batch_status_json<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<String> {
+        let reports = self.batch_status(pkgs)?;
+        Ok(serde_json::to_string(&reports).map_err(io::Error::from)?)
+    }
+
+    /// Reads a file from the upstream HEAD of a package.
+    ///
+    /// The file is read from git's object store so this works on packages that have not been
+    /// merged yet. Returns `None` if the file does not exist upstream.
+    pub fn fetch_file<S: AsRef<str>, P: AsRef<str>>(
+        &self,
+        pkg: S,
+        path: P,
+    ) -> Result<Option<Vec<u8>>> {
+        git_show_file(
+            &self.git,
+            &self.flags(),
+            self.pkg_dir(pkg.as_ref())?,
+            "HEAD@{u}",
+            path.as_ref(),
+        )
+    }
+
+    /// Checks out a single file from a commit into a package's working tree.
+    ///
+    /// This runs `git checkout <from> -- <path>`, so `from` can be `HEAD@{u}` to take upstream's
+    /// version of a file without merging everything else. The file is also staged and any changes
+    /// to it in the working tree are overwritten.
+    pub fn checkout_file<S: AsRef<str>, P: AsRef<str>>(
+        &self,
+        pkg: S,
+        path: P,
+        from: &str,
+    ) -> Result<()> {
+        self.retry_locked(|| {
+            git_command(
+                &self.git,
+                self.pkg_dir(pkg.as_ref())?,
+                &self.flags(),
+                &["checkout", from, "--", path.as_ref()],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Lists the dependencies of a package from its upstream `.SRCINFO`.
+    ///
+    /// This includes the depends, makedepends and checkdepends of the pkgbase and every
+    /// pkgname for all architectures. Version constraints are kept as is, for example
+    /// `foo>=1.0`. Each dependency is only listed once.
+    ///
+    /// Returns an empty list if the package has no `.SRCINFO`.
+    #[cfg(feature = "srcinfo")]
+    pub fn dependencies<S: AsRef<str>>(&self, pkg: S) -> Result<Vec<String>> {
+        let srcinfo = match self.upstream_srcinfo(pkg.as_ref())? {
+            Some(srcinfo) => srcinfo,
+            None => return Ok(Vec::new()),
+        };
+        let deps = srcinfo
+            .pkgs
+            .iter()
+            .chain([&srcinfo.pkg])
+            .flat_map(|pkg| &pkg.depends)
+            .chain(&srcinfo.base.makedepends)
+            .chain(&srcinfo.base.checkdepends)
+            .flat_map(|deps| &deps.vec);
+        let mut ret: Vec<String> = Vec::new();
+
+        for dep in deps {
+            if !ret.contains(dep) {
+                ret.push(dep.clone());
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Parses the upstream `.SRCINFO` of a package, returning `None` if it has none.
+    #[cfg(feature = "srcinfo")]
+    fn upstream_srcinfo(&self, pkg: &str) -> Result<Option<srcinfo::Srcinfo>> {
+        let file = match self.fetch_file(pkg, ".SRCINFO")? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let srcinfo = srcinfo::Srcinfo::parse_buf(file.as_slice())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Some(srcinfo))
+    }
+
+    /// Check if a package downloads its sources from a version control system.
+    ///
+    /// This is true if any source in the upstream `.SRCINFO` uses git, svn, hg, bzr or fossil.
+    /// Such packages fetch their sources during the build so may have new changes to build even
+    /// when the package itself has not been updated.
+    pub fn is_vcs<S: AsRef<str>>(&self, pkg: S) -> Result<bool> {
+        let srcinfo = match self.fetch_file(pkg, ".SRCINFO")? {
+            Some(srcinfo) => srcinfo,
+            None => return Ok(false),
+        };
+        let srcinfo = String::from_utf8_lossy(&srcinfo);
+
+        // Only the sources are needed so this does not need the srcinfo feature.
+        let is_vcs = srcinfo
+            .lines()
+            .filter_map(|line| line.trim().split_once(" = "))
+            .filter(|(key, _)| *key == "source" || key.starts_with("source_"))
+            .any(|(_, source)| {
+                let url = source.rsplit("::").next().unwrap_or(source);
+                let protocol = url.split(['+', ':']).next().unwrap_or(url);
+                ["git", "svn", "hg", "bzr", "fossil"].contains(&protocol)
+            });
+
+        Ok(is_vcs)
+    }
+
+    /// Diff a list of packages returning the diffs as strings.
+    ///
+    /// Diffing a package that is already up to date will generate a diff against an empty git tree
+    ///
+    /// Additionally this function gives you the ability to force color. This is useful if you
+    /// intend to print the diffs to stdout. Passing a bool forces color on or off while
+    /// [`Color::Auto`] only colors the diffs if stdout is a terminal.
+    ///
+    /// If `max_diff_bytes` is set, diffs larger than it are truncated and end with a marker
+    /// saying so. The full diff can still be viewed with [`print_diff`](fn.print_diff.html) or
+    /// [`save_diffs`](fn.save_diffs.html).
+    pub fn diff<S: AsRef<str>, C: Into<Color>>(&self, pkgs: &[S], color: C) -> Result<Vec<String>> {
+        self.diff_with(
+            pkgs,
+            color.into(),
+            self.merge_method(),
+            DiffFormat::StatAndPatch,
+        )
+    }
+
+    /// The same as [`diff`](fn.diff.html) but only shows the diffstat, leaving out the patch,
+    /// and only the subject of each commit.
+    ///
+    /// This is much cheaper for packages with large changes and is useful for giving an overview
+    /// before showing the full diff. With [`DiffMode::Merge`] the merge is done with
+    /// `git merge-tree` as in [`diff_safe`](fn.diff_safe.html), so the working tree is never
+    /// touched and git 2.38 or newer is required.
+    pub fn diff_stat<S: AsRef<str>, C: Into<Color>>(
+        &self,
+        pkgs: &[S],
+        color: C,
+    ) -> Result<Vec<String>> {
+        self.diff_with(pkgs, color.into(), MergeMethod::MergeTree, DiffFormat::Stat)
+    }
+
+    /// The same as [`diff`](fn.diff.html) but never modifies the working tree.
+    ///
+    /// With [`DiffMode::Merge`] the merge is done with `git merge-tree`, which requires git 2.38
+    /// or newer. Local changes are left alone even if the process is killed mid diff. Unlike
+    /// [`diff`](fn.diff.html) merges that conflict still produce a diff, with the conflicts shown
+    /// as conflict markers.
+    pub fn diff_safe<S: AsRef<str>, C: Into<Color>>(
+        &self,
+        pkgs: &[S],
+        color: C,
+    ) -> Result<Vec<String>> {
+        self.diff_with(
+            pkgs,
+            color.into(),
+            MergeMethod::MergeTree,
+            DiffFormat::StatAndPatch,
+        )
+    }
+
+    fn diff_with<S: AsRef<str>>(
+        &self,
+        pkgs: &[S],
+        color: Color,
+        method: MergeMethod,
+        format: DiffFormat,
+    ) -> Result<Vec<String>> {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+        let pkgs = pkgs.iter();
+        let mut ret = Vec::new();
+
+        for pkg in pkgs {
+            let path = self.pkg_dir(pkg.as_ref())?;
+            let mut log = Vec::new();
+            git_log(&self.git, &self.flags(), &path, color, format, &mut log)?;
+            let mut s: String = String::from_utf8_lossy(&log).into();
+            let mut diff = Vec::new();
+            git_diff(
+                &self.git,
+                &self.flags(),
+                &path,
+                &git_diff_args(
+                    &self.diff_paths(pkg.as_ref(), self.diff_range_with(pkg.as_ref(), method)?)?,
+                    &self.diff_flags(),
+                    format,
+                ),
+                color,
+                &mut diff,
+            )?;
+            self.untracked_diff(pkg.as_ref(), color, format, &mut diff)?;
+            let mut diff = String::from_utf8_lossy(&diff).into_owned();
+            if let Some(max) = self.diff_preview_lines {
+                diff = preview_diff(&diff, max);
+            }
+            s.push_str(&diff);
+            s.push('\n');
+            if let Some(max) = self.max_diff_bytes {
+                truncate_diff(&mut s, max);
+            }
+            ret.push(s);
+        }
+
+        Ok(ret)
+    }
+
+    /// Diff the changes that merging a package would bring in.
+    ///
+    /// Unlike [`diff`](fn.diff.html) this ignores AUR_SEEN and diffs from the point the local
+    /// HEAD and upstream diverged to the upstream HEAD. This is the patch
+    /// [`merge`](fn.merge.html) applies to the working tree, leaving out local commits.
+    pub fn merge_preview<S: AsRef<str>, C: Into<Color>>(&self, pkg: S, color: C) -> Result<String> {
+        let mut diff = Vec::new();
+        git_diff(
+            &self.git,
+            &self.flags(),
+            self.pkg_dir(pkg.as_ref())?,
+            &git_diff_args(
+                &self.diff_paths(pkg.as_ref(), vec!["HEAD...HEAD@{u}".into()])?,
+                &self.diff_flags(),
+                DiffFormat::StatAndPatch,
+            ),
+            color.into(),
+            &mut diff,
+        )?;
+        Ok(String::from_utf8_lossy(&diff).into_owned())
+    }
+
+    /// Diff a package between two revisions, such as two tags or commits.
+    ///
+    /// Unlike [`diff`](fn.diff.html) this does not depend on what has been seen or merged so it
+    /// can be used to review the changes between any two points in the package's history.
+    ///
+    /// A revision that does not name a commit fails with
+    /// [`Error::UnknownRevision`](enum.Error.html#variant.UnknownRevision).
+    pub fn diff_revs<S: AsRef<str>, C: Into<Color>>(
+        &self,
+        pkg: S,
+        from: &str,
+        to: &str,
+        color: C,
+    ) -> Result<String> {
+        let pkg = pkg.as_ref();
+        validate_name(pkg)?;
+        let range = vec![self.resolve_rev(pkg, from)?, self.resolve_rev(pkg, to)?];
+
+        let mut diff = Vec::new();
+        git_diff(
+            &self.git,
+            &self.flags(),
+            self.pkg_dir(pkg)?,
+            &git_diff_args(
+                &self.diff_paths(pkg, range)?,
+                &self.diff_flags(),
+                DiffFormat::StatAndPatch,
+            ),
+            color.into(),
+            &mut diff,
+        )?;
+        Ok(String::from_utf8_lossy(&diff).into_owned())
+    }
+
+    /// Resolves a revision to the commit it names.
+    fn resolve_rev(&self, pkg: &str, rev: &str) -> Result<String> {
+        let output = git_command_allow(
+            &self.git,
+            self.pkg_dir(pkg)?,
+            &self.flags(),
+            &[
+                "rev-parse",
+                "--verify",
+                "-q",
+                "--end-of-options",
+                &format!("{}^{{commit}}", rev),
+            ],
+            &[1],
+        )?;
+
+        if !output.status.success() {
+            return Err(Error::UnknownRevision {
+                pkg: pkg.to_string(),
+                rev: rev.to_string(),
+            });
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Counts the lines changed in each package's diff.
+    ///
+    /// This is the number of lines added plus the number of lines removed in the diff that
+    /// [`diff`](fn.diff.html) would show. It can be used to sort packages so that the largest
+    /// or smallest changes are reviewed first.
+    pub fn packages_by_diff_size<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<Vec<(String, usize)>> {
+        let mut ret = Vec::new();
+
+        for pkg in pkgs {
+            let size = git_diff_size(
+                &self.git,
+                &self.flags(),
+                self.pkg_dir(pkg.as_ref())?,
+                &self.diff_paths(pkg.as_ref(), self.diff_range(pkg.as_ref())?)?,
+            )?;
+            ret.push((pkg.as_ref().to_string(), size));
+        }
+
+        Ok(ret)
+    }
+
+    /// Diff a single package.
+    ///
+    /// Relies on `git diff` for printing. This means output will likley be coloured and ran through less.
+    /// Although this is dependent on the user's git config
+    pub fn print_diff<S: AsRef<str>>(&self, pkg: S) -> Result<()> {
+        show_git_diff(
+            &self.git,
+            &self.flags(),
+            self.pkg_dir(pkg.as_ref())?,
+            &self.diff_paths(pkg.as_ref(), self.diff_range(pkg.as_ref())?)?,
+            &self.diff_flags(),
+        )
+    }
+
+    /// Diff a list of packages and save them to diff_dir.
+    ///
+    /// Diffing a package that is already up to date will generate a diff against an empty git tree
+    ///
+    /// If [`keep_diff_history`](#structfield.keep_diff_history) is set a timestamped copy of
+    /// each diff is also kept. See [`diff_history`](fn.diff_history.html).
+    pub fn save_diffs<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+        self.mk_diff_dir()?;
+
+        for pkg in pkgs {
+            let path = self.diff_path(pkg.as_ref())?;
+            let mut files = vec![self.fs.create(&path)?];
+
+            if self.keep_diff_history {
+                let dir = self.diff_history_dir(pkg.as_ref())?;
+                let mut time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                self.fs.create_dir_all(&dir)?;
+                // Saves within the clock's resolution would otherwise overwrite each other.
+                let path = loop {
+                    let name = format!("{}.{:09}.diff", time.as_secs(), time.subsec_nanos());
+                    let path = dir.join(name);
+                    if !self.fs.exists(&path) {
+                        break path;
+                    }
+                    time += Duration::from_nanos(1);
+                };
+                files.push(self.fs.create(&path)?);
+            }
+
+            let format = if self.saved_diff_stat {
+                DiffFormat::StatAndPatch
+            } else {
+                DiffFormat::Patch
+            };
+            let mut out = BufWriter::new(Tee(files));
+            self.write_diff_with(pkg.as_ref(), Color::Never, format, &mut out)?;
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Diff a package and write the log and diff into `out`.
+    ///
+    /// This writes the same layout as [`save_diffs`](fn.save_diffs.html) but lets the caller
+    /// decide where it goes, such as a pager or a file. git's output is copied into `out` as it
+    /// is produced rather than collected in memory first. [`diff_preview_lines`](#structfield.diff_preview_lines) and
+    /// [`max_diff_bytes`](#structfield.max_diff_bytes) are not applied.
+    pub fn write_diff<S: AsRef<str>, C: Into<Color>, W: Write>(
+        &self,
+        pkg: S,
+        color: C,
+        out: &mut W,
+    ) -> Result<()> {
+        validate_name(pkg.as_ref())?;
+        self.write_diff_with(pkg.as_ref(), color.into(), DiffFormat::StatAndPatch, out)
+    }
+
+    fn write_diff_with(
+        &self,
+        pkg: &str,
+        color: Color,
+        format: DiffFormat,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        let path = self.pkg_dir(pkg)?;
+        git_log(&self.git, &self.flags(), &path, color, format, out)?;
+        out.write_all(b"\n")?;
+        git_diff(
+            &self.git,
+            &self.flags(),
+            &path,
+            &git_diff_args(
+                &self.diff_paths(pkg, self.diff_range(pkg)?)?,
+                &self.diff_flags(),
+                format,
+            ),
+            color,
+            out,
+        )?;
+        self.untracked_diff(pkg, color, format, out)
+    }
+
+    /// Lists the diffs that were kept for a package each time it was saved.
+    ///
+    /// Returns the time each diff was saved along with its path, oldest first. See
+    /// [`keep_diff_history`](#structfield.keep_diff_history).
+    pub fn diff_history<S: AsRef<str>>(&self, pkg: S) -> Result<Vec<(SystemTime, PathBuf)>> {
+        let dir = match self.fs.read_dir(&self.diff_history_dir(pkg.as_ref())?) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut ret = Vec::new();
+
+        for path in dir {
+            let time = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".diff"))
+                .and_then(parse_history_time);
+            if let Some(time) = time {
+                ret.push((UNIX_EPOCH + time, path));
+            }
+        }
+
+        ret.sort();
+        Ok(ret)
+    }
+
+    fn diff_history_dir(&self, pkg: &str) -> Result<PathBuf> {
+        validate_name(pkg)?;
+        Ok(self.diff_dir.join(".history").join(pkg))
+    }
+
+    /// Makes a view of newly downloaded files.
+    ///
+    /// This view is a dir containing the packages downloaded/fetched and diffs
+    /// for packages that have diffs.
+    ///
+    /// Files are symlinked from the cache dirs so there is no duplication of files.
+    ///
+    /// Links left in `dir` by a previous view are reused if they already point to the right
+    /// place and replaced otherwise. Listing a package more than once is not an error. A file or
+    /// directory that is not a link is never replaced and fails with
+    /// [`io::ErrorKind::AlreadyExists`].
+    pub fn make_view<P: AsRef<Path>, S1: AsRef<str>, S2: AsRef<str>>(
+        &self,
+        dir: P,
+        pkgs: &[S1],
+        diffs: &[S2],
+    ) -> Result<()> {
+        self.make_view_paths(dir, pkgs, diffs)?;
+        Ok(())
+    }
+
+    /// The same as [`make_view`](fn.make_view.html) but returns the links that were created.
+    ///
+    /// Links are only made for files that exist, so a package without a diff or a `.SRCINFO`
+    /// will have fewer entries than one with both.
+    pub fn make_view_paths<P: AsRef<Path>, S1: AsRef<str>, S2: AsRef<str>>(
+        &self,
+        dir: P,
+        pkgs: &[S1],
+        diffs: &[S2],
+    ) -> Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        let mut ret = Vec::new();
+
+        for pkg in diffs {
+            let dest = dir.join(format!("{}.diff", pkg.as_ref()));
+            let src = self.diff_path(pkg)?;
+            if self.fs.is_file(&src) {
+                self.view_link(&src, dest, &mut ret)?;
+            }
+        }
+
+        for pkg in pkgs {
+            let dest = dir.join(pkg.as_ref());
+            let pkgbuild_dest = dir.join(format!("{}.PKGBUILD", pkg.as_ref()));
+            let srcinfo_dest = dir.join(format!("{}.SRCINFO", pkg.as_ref()));
+
+            let src = self.pkg_dir(pkg.as_ref())?;
+            if src.is_dir() {
+                self.view_link(&src, dest, &mut ret)?;
+            }
+
+            let src = self.pkg_dir(pkg.as_ref())?.join("PKGBUILD");
+            if src.is_file() {
+                self.view_link(&src, pkgbuild_dest, &mut ret)?;
+            }
+
+            let src = self.pkg_dir(pkg.as_ref())?.join(".SRCINFO");
+            if src.is_file() {
+                self.view_link(&src, srcinfo_dest, &mut ret)?;
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn view_link(&self, src: &Path, dest: PathBuf, links: &mut Vec<PathBuf>) -> Result<()> {
+        if links.contains(&dest) {
+            return Ok(());
+        }
+
+        match self.fs.read_link(&dest) {
+            Ok(target) if target == src => (),
+            Ok(_) => {
+                self.fs.remove_file(&dest)?;
+                self.fs.symlink(src, &dest)?;
+            }
+            // Files and directories that are not links were not made by a view so are never
+            // replaced.
+            Err(_) if self.fs.exists(&dest) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists and is not a symlink", dest.display()),
+                )
+                .into());
+            }
+            Err(_) => self.fs.symlink(src, &dest)?,
+        }
+
+        links.push(dest);
+        Ok(())
+    }
+
+    /// Merge a list of packages with their upstream.
+    ///
+    /// A package that conflicts fails with
+    /// [`Error::MergeConflict`](enum.Error.html#variant.MergeConflict) listing the conflicted
+    /// files. What state the package is left in depends on the
+    /// [`conflict_policy`](#structfield.conflict_policy). A package left conflicted by an earlier
+    /// merge fails the same way until the conflicts are resolved, unless the policy aborts it.
+    pub fn merge<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        self.merge_cb(pkgs, |_| ())
+    }
+
+    /// Merge a list of packages with their upstream, calling callback for each merge.
+    ///
+    /// If [`merge_jobs`](#structfield.merge_jobs) is more than 1 packages are merged in parallel
+    /// and the callback is called in the order the merges complete, not the order of `pkgs`.
+    /// `n` is then the number of merges completed before this one. After a merge fails no
+    /// further merges are started and the error is returned once running merges complete.
+    pub fn merge_cb<S: AsRef<str>, F: Fn(Callback)>(&self, pkgs: &[S], cb: F) -> Result<()> {
+        self.merge_with(pkgs, false, |pkg, n, output| {
+            cb(Callback { pkg, n, output })
+        })
+    }
+
+    /// Merge a list of packages with their upstream, calling callback for each line git
+    /// outputs while merging.
+    ///
+    /// Unlike [`merge_cb`](fn.merge_cb.html) the callback is called as the merge runs rather
+    /// than once it finishes. The output of each call is a single line. Packages are merged in
+    /// parallel the same way, so lines of different packages may be interleaved.
+    pub fn merge_progress<S: AsRef<str>, F: Fn(Callback)>(&self, pkgs: &[S], cb: F) -> Result<()> {
+        self.merge_with(pkgs, true, |pkg, n, output| cb(Callback { pkg, n, output }))
+    }
+
+    /// Merges packages using [`merge_jobs`](#structfield.merge_jobs) threads.
+    ///
+    /// `cb` is called from the current thread with the output of each merge once it completes,
+    /// or with each line of output as git prints it if `lines` is set.
+    fn merge_with<S, F>(&self, pkgs: &[S], lines: bool, mut cb: F) -> Result<()>
+    where
+        S: AsRef<str>,
+        F: FnMut(&str, usize, &str),
+    {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+        let pkgs = pkgs.iter().map(|p| p.as_ref()).collect::<Vec<_>>();
+
+        if self.merge_jobs <= 1 {
+            for (n, pkg) in pkgs.into_iter().enumerate() {
+                if lines {
+                    self.merge_pkg_lines(pkg, &mut |line| cb(pkg, n, line))?;
+                } else {
+                    let output = self.merge_pkg(pkg)?;
+                    cb(pkg, n, String::from_utf8_lossy(&output.stdout).trim());
+                }
+            }
+            return Ok(());
+        }
+
+        enum Merged {
+            Line(String),
+            Done(Result<Output>),
+        }
+
+        let pkgs = &pkgs;
+        let (pkg_send, pkg_rec) = channel::bounded(0);
+        let (merged_send, merged_rec) = channel::bounded(32);
+        let stop = &AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for &pkg in pkgs {
+                    if pkg_send.send(pkg).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            for n in 0..self.merge_jobs.min(pkgs.len()) {
+                let merged_send = merged_send.clone();
+                let pkg_rec = pkg_rec.clone();
+                self.worker(n)
+                    .spawn_scoped(scope, move || {
+                        for pkg in &pkg_rec {
+                            if stop.load(Ordering::Acquire) {
+                                break;
+                            }
+                            let merge = if lines {
+                                self.merge_pkg_lines(pkg, &mut |line| {
+                                    let _ = merged_send.send((pkg, Merged::Line(line.into())));
+                                })
+                            } else {
+                                self.merge_pkg(pkg)
+                            };
+                            if merge.is_err() {
+                                stop.store(true, Ordering::Release);
+                            }
+                            let _ = merged_send.send((pkg, Merged::Done(merge)));
+                        }
+                    })
+                    .expect("failed to spawn worker thread");
+            }
+
+            drop(pkg_rec);
+            drop(merged_send);
+
+            let mut n = 0;
+            let mut err = None;
+
+            for (pkg, merged) in merged_rec {
+                match merged {
+                    Merged::Line(line) => cb(pkg, n, &line),
+                    Merged::Done(Ok(output)) => {
+                        if !lines {
+                            cb(pkg, n, String::from_utf8_lossy(&output.stdout).trim());
+                        }
+                        n += 1;
+                    }
+                    Merged::Done(Err(e)) => {
+                        err.get_or_insert(e);
+                    }
+                }
+            }
+
+            err.map_or(Ok(()), Err)
+        })
+    }
+
+    fn merge_pkg(&self, pkg: &str) -> Result<Output> {
+        self.merge_pkg_lines(pkg, &mut |_| ())
+    }
+
+    fn merge_pkg_lines(&self, pkg: &str, on_line: &mut dyn FnMut(&str)) -> Result<Output> {
+        self.retry_locked(|| self.try_merge_pkg(pkg, on_line))
+    }
+
+    fn try_merge_pkg(&self, pkg: &str, on_line: &mut dyn FnMut(&str)) -> Result<Output> {
+        let path = self.pkg_dir(pkg)?;
+        let flags = self.flags();
+
+        // A merge left conflicted by an earlier call would otherwise fail as a generic error.
+        if git_rebase_in_progress(&self.git, &flags, &path)? {
+            if self.conflict_policy == ConflictPolicy::Leave {
+                return Err(Error::MergeConflict {
+                    pkg: pkg.to_string(),
+                    files: git_conflicts(&self.git, &flags, &path)?,
+                });
+            }
+            git_command(&self.git, &path, &flags, &["rebase", "--abort"])?;
+        }
+
+        // Shallow repos may be missing the merge base even if the histories are related.
+        if !git_is_shallow(&self.git, &flags, &path)?
+            && !git_is_related(&self.git, &flags, &path, "HEAD", "HEAD@{u}")?
+        {
+            return match self.unrelated_policy {
+                UnrelatedPolicy::Error => Err(Error::UnrelatedHistories {
+                    pkg: pkg.to_string(),
+                }),
+                UnrelatedPolicy::Reset => git_reset_upstream(&self.git, &flags, &path),
+            };
+        }
+
+        let err = match git_rebase(&self.git, &flags, &path, self.merge_autostash, on_line) {
+            Ok(output) => return Ok(output),
+            Err(e) => e,
+        };
+
+        let files = match git_conflicts(&self.git, &flags, &path) {
+            Ok(files) if !files.is_empty() => files,
+            _ => return Err(err),
+        };
+
+        if self.conflict_policy != ConflictPolicy::Leave {
+            git_command(&self.git, &path, &flags, &["rebase", "--abort"])?;
+        }
+
+        if self.conflict_policy == ConflictPolicy::Theirs {
+            return git_command(&self.git, &path, &flags, &["reset", "--hard", "HEAD@{u}"]);
+        }
+
+        Err(Error::MergeConflict {
+            pkg: pkg.to_string(),
+            files,
+        })
+    }
+
+    /// Marks a list of repos as seen.
+    ///
+    /// This updates AUR_SEEN to the upstream HEAD
+    ///
+    /// If [`verify_command`](#structfield.verify_command) is set it must pass for each package
+    /// before it is marked as seen.
+    pub fn mark_seen<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+        for pkg in pkgs {
+            self.verify_pkg(pkg.as_ref())?;
+            let path = self.pkg_dir(pkg.as_ref())?;
+            git_mark_seen(&self.git, &self.flags(), path, &self.seen_ref, "HEAD")?;
+        }
+
+        Ok(())
+    }
+
+    /// Accepts the upstream changes of a list of packages after the user has reviewed them.
+    ///
+    /// This marks each package as seen at the upstream HEAD. If `merge` is true each package is
+    /// merged first and is only marked as seen once the merge succeeds.
+    ///
+    /// If [`verify_command`](#structfield.verify_command) is set it must pass for each package
+    /// before it is marked as seen. The command runs after merging so when `merge` is false it
+    /// sees the files that are currently checked out rather than the upstream ones.
+    pub fn accept<S: AsRef<str>>(&self, pkgs: &[S], merge: bool) -> Result<()> {
+        self.accept_cb(pkgs, merge, |_| ())
+    }
+
+    /// Accepts the upstream changes of a list of packages, calling callback with the output of
+    /// the verify command for each package.
+    pub fn accept_cb<S: AsRef<str>, F: Fn(Callback)>(
+        &self,
+        pkgs: &[S],
+        merge: bool,
+        cb: F,
+    ) -> Result<()> {
+        for (n, pkg) in pkgs.iter().enumerate() {
+            let path = self.pkg_dir(pkg.as_ref())?;
+            if merge {
+                self.merge_pkg(pkg.as_ref())?;
+            }
+            let output = self.verify_pkg(pkg.as_ref())?;
+            git_mark_seen(&self.git, &self.flags(), path, &self.seen_ref, "HEAD@{u}")?;
+            cb(Callback {
+                pkg: pkg.as_ref(),
+                n: n + 1,
+                output: &output,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Runs the verify command for a list of packages without marking them as seen, calling
+    /// callback with the output of each run.
+    ///
+    /// Stops at the first package that fails verification.
+    pub fn verify_cb<S: AsRef<str>, F: Fn(Callback)>(&self, pkgs: &[S], cb: F) -> Result<()> {
+        for (n, pkg) in pkgs.iter().enumerate() {
+            let output = self.verify_pkg(pkg.as_ref())?;
+            cb(Callback {
+                pkg: pkg.as_ref(),
+                n: n + 1,
+                output: &output,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn verify_pkg(&self, pkg: &str) -> Result<String> {
+        let (cmd, args) = match self.verify_command.as_deref() {
+            Some([cmd, args @ ..]) => (cmd, args),
+            _ => return Ok(String::new()),
+        };
+
+        let mut command = Command::new(cmd);
+        command
+            .current_dir(self.pkg_dir(pkg)?)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if self.command_timeout.is_some() {
+            command.process_group(0);
+        }
+
+        log_cmd(&command);
+        let child = command
+            .spawn()
+            .map_err(|e| command_err(&command, Some(e.to_string())))?;
+        let output = wait_output(&command, child, self.command_timeout, None)?;
+        let mut out = String::from_utf8_lossy(&output.stdout).into_owned();
+        out.push_str(&String::from_utf8_lossy(&output.stderr));
+        let out = out.trim().to_string();
+
+        if output.status.success() {
+            Ok(out)
+        } else {
+            // Checkers such as namcap report problems on stdout so keep all of the output.
+            Err(command_err(&command, Some(out)))
+        }
+    }
+
+    /// Resets a list of packages to their upstream HEAD and marks them as seen.
+    ///
+    /// This is destructive. Local commits, uncommitted changes and any merge in progress are
+    /// all discarded. Untracked files such as build artifacts are kept.
+    ///
+    /// If [`verify_command`](#structfield.verify_command) is set it must pass for each package
+    /// before it is marked as seen.
+    pub fn reset_to_upstream<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        for pkg in pkgs {
+            let path = self.pkg_dir(pkg.as_ref())?;
+            self.retry_locked(|| git_reset_upstream(&self.git, &self.flags(), &path))?;
+            self.verify_pkg(pkg.as_ref())?;
+            git_mark_seen(&self.git, &self.flags(), &path, &self.seen_ref, "HEAD@{u}")?;
+        }
+
+        Ok(())
+    }
+
+    /// Exports the review state of a list of packages.
+    ///
+    /// This records the commit AUR_SEEN points to for each package so it can be backed up or
+    /// moved to another machine and later restored with
+    /// [`import_state`](fn.import_state.html). Packages that have never been seen are left out.
+    pub fn export_state<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<ReviewState> {
+        let mut seen = HashMap::new();
+
+        for pkg in pkgs {
+            let path = self.pkg_dir(pkg.as_ref())?;
+            if git_has_seen(&self.git, &self.flags(), &path, &self.seen_ref)? {
+                let commit = git_rev_parse(&self.git, &self.flags(), &path, &self.seen_ref)?;
+                seen.insert(pkg.as_ref().to_string(), commit);
+            }
+        }
+
+        Ok(ReviewState { seen })
+    }
+
+    /// Restores review state exported by [`export_state`](fn.export_state.html).
+    ///
+    /// AUR_SEEN is set to the recorded commit for each package. Packages that are not cloned
+    /// are skipped. The recorded commits must exist in the package's repo, so packages should be
+    /// downloaded before their state is imported.
+    pub fn import_state(&self, state: &ReviewState) -> Result<()> {
+        for (pkg, commit) in &state.seen {
+            let path = self.pkg_dir(pkg)?;
+            if self.is_git_repo(pkg) {
+                git_mark_seen(&self.git, &self.flags(), path, &self.seen_ref, commit)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Commits changes to list of packages
+    ///
+    /// This is intended to allow saving changes made by the user after reviewing.
+    ///
+    /// Returns each package along with whether a commit was made. Packages with no changes are
+    /// skipped.
+    pub fn commit<S1: AsRef<str>, S2: AsRef<str>>(
+        &self,
+        pkgs: &[S1],
+        message: S2,
+    ) -> Result<Vec<(String, bool)>> {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+        let mut ret = Vec::with_capacity(pkgs.len());
+
+        for pkg in pkgs {
+            let path = self.pkg_dir(pkg.as_ref())?;
+            let committed = git_commit(&self.git, &self.flags(), path, message.as_ref())?;
+            ret.push((pkg.as_ref().to_string(), committed));
+        }
+
+        Ok(ret)
+    }
+
+    /// Moves the cache to a new directory, returning a handle that uses the new location.
+    ///
+    /// If the clone and diff dir are the same directory it is moved to `new_root` as with
+    /// [`with_combined_cache_dir`](fn.with_combined_cache_dir.html). Otherwise they are moved to
+    /// subdirectories named clone and diff as with [`with_cache_dir`](fn.with_cache_dir.html).
+    ///
+    /// Directories are moved with a rename so `new_root` must be on the same filesystem as the
+    /// current cache.
+    pub fn relocate<P: AsRef<Path>>(&self, new_root: P) -> Result<Fetch> {
+        let new_root = new_root.as_ref();
+        let mut fetch = self.clone();
+
+        if self.clone_dir == self.diff_dir {
+            fetch.clone_dir = new_root.into();
+            fetch.diff_dir = new_root.into();
+            if self.clone_dir.exists() {
+                if let Some(parent) = new_root.parent() {
+                    create_dir_all(parent)?;
+                }
+                rename(&self.clone_dir, new_root)?;
+            }
+            return Ok(fetch);
+        }
+
+        fetch.clone_dir = new_root.join("clone");
+        fetch.diff_dir = new_root.join("diff");
+
+        // The clone dir is always accessed directly while the diff dir goes through fs, so each
+        // creates the root on its own side.
+        let moved_clones = self.clone_dir.exists();
+        if moved_clones {
+            create_dir_all(new_root)?;
+            rename(&self.clone_dir, &fetch.clone_dir)?;
+        }
+        if self.fs.exists(&self.diff_dir) {
+            let res = self
+                .fs
+                .create_dir_all(new_root)
+                .and_then(|_| self.fs.rename(&self.diff_dir, &fetch.diff_dir));
+            if let Err(e) = res {
+                // Don't leave the cache split between the old and new root.
+                if moved_clones {
+                    rename(&fetch.clone_dir, &self.clone_dir)?;
+                }
+                return Err(e.into());
+            }
+        }
+
+        Ok(fetch)
+    }
+
+    /// Removes every cached package from the clone dir.
+    ///
+    /// Only directories that are git repos, their recorded provenance and files ending in `.diff`
+    /// are removed so it is safe to use on a cache dir that contains other files.
+    ///
+    /// Saved diffs hold the review state of packages that have not been merged yet. If
+    /// `keep_diffs` is true the diff dir is left alone so this state is preserved.
+    pub fn clean(&self, keep_diffs: bool) -> Result<()> {
+        for pkg in self.cached_packages()? {
+            remove_dir_all(self.pkg_dir(&pkg)?)?;
+            match self.fs.remove_file(&self.provenance_path(&pkg)?) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+
+        if !keep_diffs {
+            for diff in self.saved_diffs()? {
+                self.fs.remove_file(&diff)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes a list of packages from the cache.
+    ///
+    /// This deletes each package's clone along with its saved diff, diff history and recorded
+    /// provenance. Packages that are not in the cache are skipped.
+    ///
+    /// Names that could refer to a path outside of the cache, such as ones containing `/` or
+    /// `..`, are refused with
+    /// [`Error::InvalidPackageName`](enum.Error.html#variant.InvalidPackageName).
+    pub fn remove<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+
+        for pkg in pkgs {
+            let pkg = pkg.as_ref();
+            let removed = [
+                remove_dir_all(self.pkg_dir(pkg)?),
+                self.fs.remove_dir_all(&self.diff_history_dir(pkg)?),
+                self.fs.remove_file(&self.diff_path(pkg)?),
+                self.fs.remove_file(&self.provenance_path(pkg)?),
+            ];
+            for res in removed {
+                match res {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes untracked and ignored files, such as build artifacts, from a list of packages.
+    ///
+    /// This runs `git clean -dffx` in each package so the history and tracked files are left
+    /// alone. Files matching [`clean_keep`](#structfield.clean_keep) are kept. Packages that are
+    /// not cloned are an error.
+    pub fn clean_untracked<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+
+        for pkg in pkgs {
+            let path = self.pkg_dir(pkg.as_ref())?;
+            // Without a repo here git would clean whatever repo the clone dir is in.
+            if !self.is_git_repo(pkg.as_ref()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not a git repo", path.display()),
+                )
+                .into());
+            }
+
+            let mut args = vec!["clean", "-q", "-dffx"];
+            for pattern in &self.clean_keep {
+                args.push("-e");
+                args.push(pattern);
+            }
+            git_command(&self.git, &path, &self.flags(), &args)?;
+        }
+
+        Ok(())
+    }
+
+    /// Lists the packages that have a saved diff but are no longer in the clone dir.
+    pub fn orphaned_diffs(&self) -> Result<Vec<String>> {
+        let mut ret = Vec::new();
+
+        for diff in self.saved_diffs()? {
+            let name = diff.file_name().unwrap().to_string_lossy();
+            let pkg = name.strip_suffix(".diff").unwrap_or(&name);
+            if !self.is_git_repo(pkg) {
+                ret.push(pkg.to_string());
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Removes saved diffs for packages that are no longer in the clone dir.
+    ///
+    /// See [`orphaned_diffs`](fn.orphaned_diffs.html).
+    pub fn prune_diffs(&self) -> Result<()> {
+        for pkg in self.orphaned_diffs()? {
+            self.fs.remove_file(&self.diff_path(&pkg)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn saved_diffs(&self) -> Result<Vec<PathBuf>> {
+        let dir = match self.fs.read_dir(&self.diff_dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut ret = Vec::new();
+
+        for path in dir {
+            if path.extension() == Some(OsStr::new("diff")) && self.fs.is_file(&path) {
+                ret.push(path);
+            }
+        }
+
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Checks that git can be ran and returns its version, such as `2.47.0`.
+    ///
+    /// This can be called before anything else to give a clear error when git is not installed.
+    /// If [`git`](#structfield.git) can not be executed this fails with
+    /// [`Error::GitNotFound`](enum.Error.html#variant.GitNotFound).
+    pub fn check_git(&self) -> Result<String> {
+        let mut command = Command::new(&self.git);
+        command.current_dir(current_dir()?).arg("--version");
+        log_cmd(&command);
+        let output = command
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|_| Error::GitNotFound(self.git.clone()))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.trim().strip_prefix("git version ") {
+            Some(version) if output.status.success() => Ok(version.to_string()),
+            _ => Err(command_err(
+                &command,
+                Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+            )),
+        }
+    }
+
+    /// Check if a package is already cloned.
+    ///
+    /// The package dir may be a symlink, and `.git` may be a file pointing to the real git dir
+    /// as is the case for worktrees and submodules. Invalid package names are never cloned.
+    pub fn is_git_repo<S: AsRef<str>>(&self, pkg: S) -> bool {
+        let git = match self.pkg_dir(pkg.as_ref()) {
+            Ok(dir) => dir.join(".git"),
+            Err(_) => return false,
+        };
+        git.is_dir() || git.is_file()
+    }
+
+    /// Checks if the clone dir is on a case-insensitive filesystem.
+    ///
+    /// On such filesystems packages whose names differ only by case share the same clone dir.
+    /// This is detected by creating two temporary files in the clone dir that differ only by
+    /// case.
+    pub fn case_insensitive(&self) -> Result<bool> {
+        self.mk_clone_dir()?;
+
+        let lower = self.clone_dir.join(".aur-fetch-case-test");
+        let upper = self.clone_dir.join(".AUR-FETCH-CASE-TEST");
+        let create = |path: &Path| OpenOptions::new().write(true).create_new(true).open(path);
+
+        create(&lower)?;
+        let insensitive = match create(&upper) {
+            Ok(_) => {
+                remove_file(&upper)?;
+                false
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => true,
+            Err(e) => {
+                let _ = remove_file(&lower);
+                return Err(e.into());
+            }
+        };
+        remove_file(&lower)?;
+
+        Ok(insensitive)
+    }
+
+    /// Lists the packages that are currently cloned in the clone dir.
+    ///
+    /// The returned list is sorted by name. If the clone dir does not exist an empty list is
+    /// returned.
+    pub fn cached_packages(&self) -> Result<Vec<String>> {
+        let dir = match read_dir(&self.clone_dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut ret = Vec::new();
+
+        for entry in dir {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if self.is_git_repo(name) {
+                    ret.push(name.to_string());
+                }
+            }
+        }
+
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Fetches every package in the clone dir.
+    ///
+    /// This is the same as passing [`cached_packages`](fn.cached_packages.html) to
+    /// [`download`](fn.download.html). Packages are fetched from the remote they were cloned
+    /// from.
+    pub fn download_cached(&self) -> Result<Vec<String>> {
+        self.download_repos_cb(&self.cached_repos()?, |_| ())
+    }
+
+    /// Builds a [`Repo`] for each package in the clone dir pointing at the remote it was cloned
+    /// from, so that repos that are not from the AUR are not pointed at it.
+    fn cached_repos(&self) -> Result<Vec<Repo>> {
+        let mut repos = self.pkg_repos(&self.cached_packages()?);
+        for repo in &mut repos {
+            if let Some(url) = self.origin_url(&repo.name)? {
+                repo.url = url;
+            }
+        }
+        Ok(repos)
+    }
+
+    /// The URL of a package's origin, or `None` if it is not one git would accept.
+    fn origin_url(&self, pkg: &str) -> Result<Option<Url>> {
+        let path = self.pkg_dir(pkg)?;
+        let output = git_command(
+            &self.git,
+            &path,
+            &self.flags(),
+            &["remote", "get-url", "origin"],
+        )?;
+        let remote = String::from_utf8_lossy(&output.stdout);
+        Ok(parse_remote(&path, remote.trim()))
+    }
+
+    /// Fetches the full history of packages that were cloned shallow.
+    ///
+    /// Packages that are not shallow are skipped.
+    pub fn unshallow<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        let flags = self.download_flags();
+
+        for pkg in pkgs {
+            let path = self.pkg_dir(pkg.as_ref())?;
+            if git_is_shallow(&self.git, &flags, &path)? {
+                git_command(&self.git, &path, &flags, &["fetch", "--unshallow"])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds packages that look like they have been moved on the AUR.
+    ///
+    /// When a package is deleted or merged into another pkgbase the AUR keeps serving its repo,
+    /// but with no commits. This queries the remote of each package with `git ls-remote --symref`
+    /// and returns the packages whose remote is empty or whose HEAD no longer points to the
+    /// branch recorded in `refs/remotes/origin/HEAD` when it was cloned. Clones that follow some
+    /// other branch, such as on a mirror with a branch per package, are instead returned when
+    /// that branch is gone. A warning is logged for each of them.
+    ///
+    /// The packages must already be downloaded.
+    pub fn moved<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<Vec<String>> {
+        let flags = self.download_flags();
+        let mut moved = Vec::new();
+
+        for pkg in pkgs {
+            let pkg = pkg.as_ref();
+            let path = self.pkg_dir(pkg)?;
+            let (expected, follows_head) = self.followed_ref(&path, &flags)?;
+
+            if !follows_head {
+                let output = git_command(
+                    &self.git,
+                    &path,
+                    &flags,
+                    &["ls-remote", "origin", &expected],
+                )?;
+                if !output.stdout.is_empty() {
+                    continue;
+                }
+                log::warn!(
+                    "{} has no {} upstream, it may have been merged or deleted",
+                    pkg,
+                    expected
+                );
+                moved.push(pkg.to_string());
+                continue;
+            }
+
+            let output = git_command(
+                &self.git,
+                &path,
+                &flags,
+                &["ls-remote", "--symref", "origin", "HEAD"],
+            )?;
+            let output = String::from_utf8_lossy(&output.stdout);
+
+            let head = output
+                .lines()
+                .find_map(|line| line.strip_prefix("ref: "))
+                .and_then(|line| line.split_whitespace().next());
+
+            match head {
+                None if output.trim().is_empty() => {
+                    log::warn!(
+                        "{} has no commits upstream, it may have been merged or deleted",
+                        pkg
+                    )
+                }
+                Some(head) if head != expected => {
+                    log::warn!(
+                        "{} upstream HEAD points to {}, it may have moved",
+                        pkg,
+                        head
+                    )
+                }
+                _ => continue,
+            }
+
+            moved.push(pkg.to_string());
+        }
+
+        Ok(moved)
+    }
+
+    /// Returns the remote ref the clone at `path` follows and whether that is the remote's HEAD.
+    fn followed_ref(&self, path: &Path, flags: &GitFlags) -> Result<(String, bool)> {
+        let config = |key: &str| -> Result<Option<String>> {
+            let output = git_command_allow(&self.git, path, flags, &["config", key], &[1])?;
+            let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Ok(Some(value).filter(|value| !value.is_empty()))
+        };
+
+        let head = git_command_allow(
+            &self.git,
+            path,
+            flags,
+            &["symbolic-ref", "-q", "refs/remotes/origin/HEAD"],
+            &[1],
+        )?;
+        let head = String::from_utf8_lossy(&head.stdout)
+            .trim()
+            .strip_prefix("refs/remotes/origin/")
+            .map(|branch| format!("refs/heads/{}", branch));
+
+        // Pinned repos track a local ref instead.
+        let tracked = match config("branch.master.remote")?.as_deref() {
+            Some("origin") => config("branch.master.merge")?,
+            _ => None,
         };
-        log_cmd(&command);
-        let output = command
-            .output()
-            .map_err(|e| command_err(&command, Some(e.to_string())))?;
 
-        if !output.status.success() {
-            return Err(command_err(
-                &command,
-                Some(String::from_utf8_lossy(&output.stderr).into_owned()),
-            ));
+        match (head, tracked) {
+            (head, Some(tracked)) if head.as_ref() != Some(&tracked) => Ok((tracked, false)),
+            (head, _) => Ok((head.unwrap_or_else(|| "refs/heads/master".into()), true)),
         }
-
-        Ok((fetched, output.stderr))
     }
 
-    /// Filters a list of packages, keep ones that have a diff.
+    /// Merges every package in the clone dir that has upstream changes.
     ///
-    /// A reoo has a diff if AUR_SEEN is defined and is different to the upstram HEAD.
-    pub fn has_diff<'a, S: AsRef<str>>(&self, pkgs: &'a [S]) -> Result<Vec<&'a str>> {
-        let mut ret = Vec::new();
+    /// Returns the packages that were merged.
+    pub fn merge_cached(&self) -> Result<Vec<String>> {
+        let mut pkgs = Vec::new();
 
-        for pkg in pkgs {
-            if git_has_diff(
-                &self.git,
-                &self.git_flags,
-                self.clone_dir.join(pkg.as_ref()),
-            )? {
-                ret.push(pkg.as_ref());
+        for pkg in self.cached_packages()? {
+            if self.ahead_behind(&pkg)?.1 > 0 {
+                pkgs.push(pkg);
             }
         }
 
-        Ok(ret)
+        self.merge(&pkgs)?;
+        Ok(pkgs)
     }
 
-    /// Filterrs a list of packages, keeping ones that have not yet been seen.
+    /// Iterates over the cached packages.
     ///
-    /// A repo is seen if AUR_SEEN exists and is equal to the upstram HEAD.
-    pub fn unseen<'a, S: AsRef<str>>(&self, pkgs: &'a [S]) -> Result<Vec<&'a str>> {
+    /// The status of each package is only computed when requested so this is cheap even for a
+    /// large cache.
+    pub fn iter_packages(&self) -> Result<impl Iterator<Item = CachedPackage<'_>>> {
+        Ok(self
+            .cached_packages()?
+            .into_iter()
+            .map(move |name| CachedPackage { fetch: self, name }))
+    }
+
+    /// Lists the cached packages that have been reviewed at least once.
+    ///
+    /// A package has been reviewed if AUR_SEEN is defined, regardless of whether it is equal to
+    /// the upstream HEAD.
+    pub fn reviewed_packages(&self) -> Result<Vec<String>> {
         let mut ret = Vec::new();
 
-        for pkg in pkgs {
-            if git_unseen(
+        for pkg in self.cached_packages()? {
+            if git_has_seen(
                 &self.git,
-                &self.git_flags,
-                self.clone_dir.join(pkg.as_ref()),
+                &self.flags(),
+                self.pkg_dir(&pkg)?,
+                &self.seen_ref,
             )? {
-                ret.push(pkg.as_ref());
+                ret.push(pkg);
             }
         }
 
         Ok(ret)
     }
 
-    /// Diff a list of packages returning the diffs as strings.
-    ///
-    /// Diffing a package that is already up to date will generate a diff against an empty git tree
-    ///
-    /// Additionally this function gives you the ability to force color. This is useful if you
-    /// intend to print the diffs to stdout.
-    pub fn diff<S: AsRef<str>>(&self, pkgs: &[S], color: bool) -> Result<Vec<String>> {
-        let pkgs = pkgs.iter();
-        let mut ret = Vec::new();
+    /// Flags passed to every git command.
+    fn flags(&self) -> GitFlags {
+        let mut flags = self.git_flags.clone();
+        if self.performance_config {
+            flags.extend(PERFORMANCE_CONFIG.iter().map(|s| s.to_string()));
+        }
+        GitFlags {
+            args: flags,
+            env: Vec::new(),
+            timeout: self.command_timeout,
+            cancel: None,
+        }
+    }
 
-        for pkg in pkgs {
-            let output = git_log(
-                &self.git,
-                &self.git_flags,
-                self.clone_dir.join(pkg.as_ref()),
-                color,
-            )?;
-            let mut s: String = String::from_utf8_lossy(&output.stdout).into();
-            let output = git_diff(
-                &self.git,
-                &self.git_flags,
-                self.clone_dir.join(pkg.as_ref()),
-                color,
-            )?;
-            s.push_str(&String::from_utf8_lossy(&output.stdout));
-            s.push('\n');
-            ret.push(s);
+    /// Runs `f`, retrying it according to the [`lock_policy`](#structfield.lock_policy) when it
+    /// fails because of a lock file.
+    fn retry_locked<T, F: FnMut() -> Result<T>>(&self, mut f: F) -> Result<T> {
+        let start = Instant::now();
+
+        loop {
+            let err = match f() {
+                Err(Error::CommandFailed(e)) => e,
+                res => return res,
+            };
+            let lock = match err.stderr.as_deref().and_then(lock_file) {
+                Some(lock) => lock,
+                None => return Err(Error::CommandFailed(err)),
+            };
+
+            match self.lock_policy {
+                LockPolicy::Fail => return Err(Error::CommandFailed(err)),
+                LockPolicy::Wait(timeout) if start.elapsed() >= timeout => {
+                    return Err(Error::CommandFailed(err))
+                }
+                LockPolicy::Wait(_) => (),
+                LockPolicy::RemoveStale(age) => {
+                    let stale = metadata(&lock)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok())
+                        .is_some_and(|elapsed| elapsed >= age);
+                    if stale {
+                        log::warn!("removing stale lock {}", lock.display());
+                        match remove_file(&lock) {
+                            Ok(()) => continue,
+                            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_millis(100));
         }
+    }
 
-        Ok(ret)
+    fn worker(&self, n: usize) -> thread::Builder {
+        let builder = thread::Builder::new().name(format!("{}-{}", self.worker_name, n));
+        match self.worker_stack_size {
+            Some(size) => builder.stack_size(size),
+            None => builder,
+        }
     }
 
-    /// Diff a single package.
-    ///
-    /// Relies on `git diff` for printing. This means output will likley be coloured and ran through less.
-    /// Although this is dependent on the user's git config
-    pub fn print_diff<S: AsRef<str>>(&self, pkg: S) -> Result<()> {
-        show_git_diff(
-            &self.git,
-            &self.git_flags,
-            self.clone_dir.join(pkg.as_ref()),
-        )
+    fn merge_method(&self) -> MergeMethod {
+        if self.use_worktrees {
+            MergeMethod::Worktree
+        } else {
+            MergeMethod::Reset
+        }
     }
 
-    /// Diff a list of packages and save them to diff_dir.
-    ///
-    /// Diffing a package that is already up to date will generate a diff against an empty git tree
-    pub fn save_diffs<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
-        self.mk_diff_dir()?;
+    /// The revisions to diff between to show the changes since the package was last seen.
+    fn diff_range(&self, pkg: &str) -> Result<Vec<String>> {
+        self.diff_range_with(pkg, self.merge_method())
+    }
 
-        for pkg in pkgs {
-            let mut path = self.diff_dir.join(pkg.as_ref());
-            path.set_extension("diff");
+    fn diff_range_with(&self, pkg: &str, method: MergeMethod) -> Result<Vec<String>> {
+        let path = self.pkg_dir(pkg)?;
 
-            let mut file = File::create(path)?;
+        if !git_has_seen(&self.git, &self.flags(), &path, &self.seen_ref)? {
+            return Ok(vec![
+                "4b825dc642cb6eb9a060e54bf8d69288fbee4904..HEAD@{u}".into()
+            ]);
+        }
 
-            file.write_all(
-                &git_log(
-                    &self.git,
-                    &self.git_flags,
-                    self.clone_dir.join(pkg.as_ref()),
-                    false,
-                )?
-                .stdout,
-            )?;
-            file.write_all(b"\n")?;
-            file.write_all(
-                &git_diff(
+        // The history between AUR_SEEN and upstream may be missing so merging may not be
+        // possible. Diffing the two commits directly only needs the commits themselves.
+        if git_is_shallow(&self.git, &self.flags(), &path)? {
+            let seen = format!("{}^{{commit}}", self.seen_ref);
+            if git_command(&self.git, &path, &self.flags(), &["cat-file", "-e", &seen]).is_err() {
+                return Err(Error::ShallowRepo {
+                    pkg: pkg.to_string(),
+                });
+            }
+            return Ok(vec![self.seen_ref.clone(), "HEAD@{u}".into()]);
+        }
+
+        match self.diff_mode {
+            // Unrelated histories can not be merged so show everything that changed instead.
+            DiffMode::Merge
+                if !git_is_related(
                     &self.git,
-                    &self.git_flags,
-                    self.clone_dir.join(pkg.as_ref()),
-                    false,
-                )?
-                .stdout,
-            )?;
+                    &self.flags(),
+                    &path,
+                    &self.seen_ref,
+                    "HEAD@{u}",
+                )? =>
+            {
+                Ok(vec![self.seen_ref.clone(), "HEAD@{u}".into()])
+            }
+            DiffMode::Merge => {
+                let tree = self.retry_locked(|| {
+                    git_merge_tree(
+                        &self.git,
+                        &self.flags(),
+                        &path,
+                        &self.seen_ref,
+                        &self.merge_cache,
+                        method,
+                    )
+                })?;
+                Ok(vec![self.seen_ref.clone(), tree])
+            }
+            DiffMode::Range => Ok(vec![self.seen_ref.clone(), "HEAD@{u}".into()]),
         }
+    }
 
-        Ok(())
+    /// Flags passed to git commands that clone or fetch.
+    fn download_flags(&self) -> GitFlags {
+        let mut flags = self.flags();
+        flags.extend(self.download_config());
+        flags.env = self.download_env();
+        flags.cancel = Some(self.cancel.clone());
+        flags
     }
 
-    /// Makes a view of newly downloaded files.
-    ///
-    /// This view is a dir containing the packages downloaded/fetched and diffs
-    /// for packages that have diffs.
+    /// Config passed to git with `-c` when cloning and fetching.
+    fn download_config(&self) -> Vec<String> {
+        let mut config = Vec::new();
+        if self.performance_config {
+            config.extend(PERFORMANCE_CONFIG.iter().map(|s| s.to_string()));
+        }
+        if let Some(version) = self.protocol_version {
+            config.push("-c".to_string());
+            config.push(format!("protocol.version={}", version));
+        }
+        config
+    }
+
+    /// Config passed to git through the environment when cloning and fetching.
     ///
-    /// Files are symlinked from the cache dirs so there is no duplication of files.
-    pub fn make_view<P: AsRef<Path>, S1: AsRef<str>, S2: AsRef<str>>(
+    /// Headers and the proxy may hold credentials, which on the command line would show up in
+    /// the process list, the logs and errors.
+    fn download_env(&self) -> Vec<(String, OsString)> {
+        let config = self
+            .http_headers
+            .iter()
+            .map(|header| ("http.extraHeader", header))
+            .chain(self.proxy.iter().map(|proxy| ("http.proxy", proxy)));
+        let mut env = Vec::new();
+
+        for (n, (key, value)) in config.enumerate() {
+            env.push((format!("GIT_CONFIG_KEY_{}", n), key.into()));
+            env.push((format!("GIT_CONFIG_VALUE_{}", n), value.into()));
+        }
+        if !env.is_empty() {
+            let count = (env.len() / 2).to_string();
+            env.push(("GIT_CONFIG_COUNT".into(), count.into()));
+        }
+
+        env
+    }
+
+    fn untracked_diff(
         &self,
-        dir: P,
-        pkgs: &[S1],
-        diffs: &[S2],
+        pkg: &str,
+        color: Color,
+        format: DiffFormat,
+        out: &mut dyn Write,
     ) -> Result<()> {
-        let dir = dir.as_ref();
-
-        for pkg in diffs {
-            let pkg = format!("{}.diff", pkg.as_ref());
-            let dest = dir.join(&pkg);
-            let src = self.diff_dir.join(&pkg);
-            if src.is_file() {
-                symlink(src, dest)?;
-            }
+        if !self.diff_untracked {
+            return Ok(());
         }
 
-        for pkg in pkgs {
-            let dest = dir.join(pkg.as_ref());
-            let pkgbuild_dest = dir.join(format!("{}.PKGBUILD", pkg.as_ref()));
-            let srcinfo_dest = dir.join(format!("{}.SRCINFO", pkg.as_ref()));
+        // Only the intent to add entries are additions, anything else is a tracked file.
+        let mut range = vec!["--diff-filter=A".to_string(), "--".to_string()];
+        range.extend(self.diff_exclude.iter().map(|path| format!(":!{}", path)));
+        git_diff_untracked(
+            &self.git,
+            &self.flags(),
+            self.pkg_dir(pkg)?,
+            &git_diff_args(&range, &self.diff_flags(), format),
+            color,
+            out,
+        )
+    }
 
-            let src = self.clone_dir.join(pkg.as_ref());
-            if src.is_dir() {
-                symlink(src, dest)?;
-            }
+    /// Appends the paths to diff to a diff range.
+    ///
+    /// The paths in `diff_exclude` are left out, unless `srcinfo_fallback` is set and nothing
+    /// else changed.
+    fn diff_paths(&self, pkg: &str, mut range: Vec<String>) -> Result<Vec<String>> {
+        range.push("--".into());
+        let len = range.len();
+        range.extend(self.diff_exclude.iter().map(|path| format!(":!{}", path)));
 
-            let src = self.clone_dir.join(pkg.as_ref()).join("PKGBUILD");
-            if src.is_file() {
-                symlink(src, pkgbuild_dest)?;
-            }
+        if self.srcinfo_fallback
+            && !git_diff_changed(&self.git, &self.flags(), self.pkg_dir(pkg)?, &range)?
+        {
+            range.truncate(len);
+        }
 
-            let src = self.clone_dir.join(pkg.as_ref()).join(".SRCINFO");
-            if src.is_file() {
-                symlink(src, srcinfo_dest)?;
-            }
+        Ok(range)
+    }
+
+    fn diff_flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(algorithm) = &self.diff_algorithm {
+            flags.push(format!("--diff-algorithm={}", algorithm));
+        }
+        if let Some(similarity) = self.find_renames {
+            flags.push(format!("-M{}%", similarity));
+        }
+        if self.find_copies {
+            flags.push("-C".into());
         }
+        if let Some(format) = &self.diff_submodule {
+            flags.push(format!("--submodule={}", format));
+        }
+        flags
+    }
 
-        Ok(())
+    pub(crate) fn mk_clone_dir(&self) -> io::Result<()> {
+        create_dir_all(&self.clone_dir)
     }
 
-    /// Merge a list of packages with their upstream.
-    pub fn merge<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
-        self.merge_cb(pkgs, |_| ())
+    fn mk_diff_dir(&self) -> io::Result<()> {
+        self.fs.create_dir_all(&self.diff_dir)
     }
+}
 
-    /// Merge a list of packages with their upstream, calling callback for each merge.
-    pub fn merge_cb<S: AsRef<str>, F: Fn(Callback)>(&self, pkgs: &[S], cb: F) -> Result<()> {
-        let pkgs = pkgs.iter();
+/// Checks that a package name can be safely joined onto the cache dirs.
+///
+/// Names come from the AUR so must not be able to refer to anything outside of the cache, nor
+/// be taken as an option when passed to git.
+pub(crate) fn validate_name(pkg: &str) -> Result<()> {
+    if pkg.is_empty()
+        || pkg == "."
+        || pkg.contains("..")
+        || pkg.contains('\0')
+        || pkg.starts_with('-')
+        || pkg.contains(is_separator)
+        || Path::new(pkg).has_root()
+    {
+        return Err(Error::InvalidPackageName(pkg.to_string()));
+    }
+    Ok(())
+}
 
-        for (n, pkg) in pkgs.enumerate() {
-            let path = self.clone_dir.join(pkg.as_ref());
-            let output = git_rebase(&self.git, &self.git_flags, path)?;
-            cb(Callback {
-                pkg: pkg.as_ref(),
-                n,
-                output: String::from_utf8_lossy(&output.stdout).trim(),
-            });
+/// Removes repeated repos so that each clone dir is only downloaded into once.
+///
+/// Repos with the same name but different URLs, references or branches would race into the same
+/// directory so are an error.
+pub(crate) fn unique_repos(repos: &[Repo]) -> Result<Vec<&Repo>> {
+    let mut seen = HashMap::new();
+    let mut ret = Vec::with_capacity(repos.len());
+
+    for repo in repos {
+        let source = (&repo.url, &repo.reference, &repo.branch);
+        match seen.insert(repo.name.as_str(), source) {
+            Some(other) if other != source => {
+                return Err(Error::DuplicateRepo {
+                    name: repo.name.clone(),
+                })
+            }
+            Some(_) => (),
+            None => ret.push(repo),
         }
+    }
 
-        Ok(())
+    Ok(ret)
+}
+
+/// Parses the time since the epoch a history diff was saved at from its name.
+///
+/// Names are `<secs>.<nanos>`, or just `<secs>` for diffs saved by older versions.
+fn parse_history_time(name: &str) -> Option<Duration> {
+    match name.split_once('.') {
+        Some((secs, nanos)) if nanos.len() == 9 => {
+            Some(Duration::new(secs.parse().ok()?, nanos.parse().ok()?))
+        }
+        Some(_) => None,
+        None => Some(Duration::from_secs(name.parse().ok()?)),
     }
+}
 
-    /// Marks a list of repos as seen.
-    ///
-    /// This updates AUR_SEEN to the upstream HEAD
-    pub fn mark_seen<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
-        for pkg in pkgs {
-            let path = self.clone_dir.join(pkg.as_ref());
-            git_mark_seen(&self.git, &self.git_flags, path)?;
+/// Limits each file in a diff to the first `max` lines of its hunks.
+fn preview_diff(diff: &str, max: usize) -> String {
+    let mut ret = String::with_capacity(diff.len());
+    let mut in_hunks = false;
+    let mut lines = 0;
+    let mut elided = 0;
+
+    let push_elided = |ret: &mut String, elided: usize| {
+        if elided != 0 {
+            ret.push_str(&format!("... {} more lines\n", elided));
         }
+    };
 
-        Ok(())
-    }
+    for line in diff.split_inclusive('\n') {
+        let plain = strip_color(line);
 
-    /// Commits changes to list of packages
-    ///
-    /// This is intended to allow saving changes made by the user after reviewing.
-    pub fn commit<S1: AsRef<str>, S2: AsRef<str>>(&self, pkgs: &[S1], message: S2) -> Result<()> {
-        for pkg in pkgs {
-            let path = self.clone_dir.join(pkg.as_ref());
-            git_commit(&self.git, &self.git_flags, path, message.as_ref())?;
+        if plain.starts_with("diff --git ") {
+            push_elided(&mut ret, elided);
+            in_hunks = false;
+            lines = 0;
+            elided = 0;
+        } else if plain.starts_with("@@") {
+            in_hunks = true;
         }
 
-        Ok(())
+        if in_hunks {
+            if lines >= max {
+                elided += 1;
+                continue;
+            }
+            lines += 1;
+        }
+
+        ret.push_str(line);
     }
 
-    /// Check if a package is already cloned.
-    pub fn is_git_repo<S: AsRef<str>>(&self, pkg: S) -> bool {
-        self.clone_dir.join(pkg.as_ref()).join(".git").is_dir()
+    push_elided(&mut ret, elided);
+    ret
+}
+
+/// Strips leading ANSI color codes from a line.
+fn strip_color(mut line: &str) -> &str {
+    while let Some(rest) = line.strip_prefix("\x1b[") {
+        match rest.find('m') {
+            Some(end) => line = &rest[end + 1..],
+            None => break,
+        }
     }
+    line
+}
 
-    fn mk_clone_dir(&self) -> io::Result<()> {
-        create_dir_all(&self.clone_dir)
+fn truncate_diff(diff: &mut String, max: usize) {
+    if diff.len() <= max {
+        return;
     }
 
-    fn mk_diff_dir(&self) -> io::Result<()> {
-        create_dir_all(&self.diff_dir)
+    let len = diff.len();
+    let mut end = max;
+    while !diff.is_char_boundary(end) {
+        end -= 1;
     }
+    diff.truncate(end);
+    diff.push_str(&format!(
+        "\n[diff truncated: showing {} of {} bytes]\n",
+        end, len
+    ));
 }
 
-fn color_str(color: bool) -> &'static str {
+fn color_str(color: Color) -> &'static str {
+    let color = match color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => io::stdout().is_terminal(),
+    };
+
     if color {
         "--color=always"
     } else {
@@ -472,30 +4133,245 @@ fn color_str(color: bool) -> &'static str {
     }
 }
 
-fn git_command<S: AsRef<OsStr>, P: AsRef<Path>>(
+fn git_command<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    path: P,
+    flags: &GitFlags,
+    args: &[&str],
+) -> Result<Output> {
+    git_command_allow(git, path, flags, args, &[])
+}
+
+/// Runs a git command treating the exit codes in `allow` as success along with 0.
+fn git_command_allow<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    path: P,
+    flags: &GitFlags,
+    args: &[&str],
+    allow: &[i32],
+) -> Result<Output> {
+    git_command_with(git, path, flags, args, allow).map(|(output, _)| output)
+}
+
+/// Runs a git command, also returning how long it took to spawn.
+fn git_command_with<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    path: P,
+    flags: &GitFlags,
+    args: &[&str],
+    allow: &[i32],
+) -> Result<(Output, Duration)> {
+    let mut command = Command::new(git.as_ref());
+    command
+        .current_dir(path.as_ref())
+        .args(flags.iter())
+        .args(args)
+        .env("GIT_TERMINAL_PROMPT", "0");
+    flags.set_env(&mut command);
+    flags.own_group(&mut command);
+
+    log_cmd(&command);
+    let start = Instant::now();
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| command_err(&command, Some(e.to_string())))?;
+    let spawn = start.elapsed();
+    let output = wait_output(&command, child, flags.timeout, flags.cancel.as_ref())?;
+
+    let allowed = output.status.code().is_some_and(|c| allow.contains(&c));
+    if output.status.success() || allowed {
+        Ok((output, spawn))
+    } else {
+        Err(command_err(
+            &command,
+            Some(String::from_utf8_lossy(&output.stderr).into()),
+        ))
+    }
+}
+
+/// Runs a git command, copying its stdout into `out` as it is output.
+fn git_command_to<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    path: P,
+    flags: &GitFlags,
+    args: &[&str],
+    out: &mut dyn Write,
+) -> Result<()> {
+    let mut command = Command::new(git.as_ref());
+    command
+        .current_dir(path.as_ref())
+        .args(flags.iter())
+        .args(args)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    flags.set_env(&mut command);
+    flags.own_group(&mut command);
+
+    log_cmd(&command);
+    let mut child = command
+        .spawn()
+        .map_err(|e| command_err(&command, Some(e.to_string())))?;
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let deadline = flags.timeout.map(|timeout| Instant::now() + timeout);
+    let cancel = flags.cancel.as_ref();
+    let pid = child.id();
+
+    let (copied, stderr, killed) = std::thread::scope(|scope| {
+        let stderr = scope.spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        });
+        let (done, finished) = channel::bounded::<()>(0);
+        // Kills git's process group on timeout or cancellation, which closes its stdout and ends
+        // the copy.
+        let watchdog = scope.spawn(move || {
+            if deadline.is_none() && cancel.is_none() {
+                return false;
+            }
+            while let Err(RecvTimeoutError::Timeout) =
+                finished.recv_timeout(Duration::from_millis(10))
+            {
+                if cancel.is_some_and(|cancel| cancel.is_cancelled())
+                    || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    // git is not reaped until after the copy so its pid can not have been reused.
+                    kill_group(pid);
+                    return true;
+                }
+            }
+            false
+        });
+
+        let copied = io::copy(&mut stdout, out);
+        if copied.is_err() {
+            kill_child(&mut child);
+        }
+        drop(done);
+        (copied, stderr.join().ok(), watchdog.join().unwrap_or(false))
+    });
+
+    let status = wait_child(&command, &mut child, None, None)?;
+    if killed && cancel.is_some_and(|cancel| cancel.is_cancelled()) {
+        return Err(Error::Cancelled {
+            fetched: Vec::new(),
+        });
+    } else if killed {
+        return Err(timeout_err(&command));
+    }
+    copied.map_err(|e| command_err(&command, Some(e.to_string())))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        let stderr = stderr.and_then(|stderr| stderr.ok()).unwrap_or_default();
+        Err(command_err(
+            &command,
+            Some(String::from_utf8_lossy(&stderr).into()),
+        ))
+    }
+}
+
+/// Runs a git command, calling `on_line` for each line of stdout and stderr as it is output.
+fn git_command_lines<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
     path: P,
-    flags: &[String],
+    flags: &GitFlags,
     args: &[&str],
+    on_line: &mut dyn FnMut(&str),
 ) -> Result<Output> {
     let mut command = Command::new(git.as_ref());
     command
         .current_dir(path.as_ref())
-        .args(flags)
+        .args(flags.iter())
         .args(args)
-        .env("GIT_TERMINAL_PROMPT", "0");
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    flags.set_env(&mut command);
+    flags.own_group(&mut command);
 
     log_cmd(&command);
-    let output = command
-        .output()
+    let mut child = command
+        .spawn()
         .map_err(|e| command_err(&command, Some(e.to_string())))?;
+    let pipes: [(Box<dyn Read + Send>, bool); 2] = [
+        (Box::new(child.stdout.take().unwrap()), false),
+        (Box::new(child.stderr.take().unwrap()), true),
+    ];
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let deadline = flags.timeout.map(|timeout| Instant::now() + timeout);
+    let mut timed_out = false;
 
-    if output.status.success() {
-        Ok(output)
+    std::thread::scope(|scope| {
+        let (line_send, line_rec) = channel::unbounded();
+
+        for (pipe, is_stderr) in pipes {
+            let line_send = line_send.clone();
+            scope.spawn(move || {
+                let mut pipe = BufReader::new(pipe);
+                let mut line = Vec::new();
+                while matches!(pipe.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+                    let _ = line_send.send((is_stderr, std::mem::take(&mut line)));
+                }
+            });
+        }
+        drop(line_send);
+
+        loop {
+            let (is_stderr, line) = match deadline {
+                Some(deadline) => match line_rec.recv_deadline(deadline) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => {
+                        // The pipes close once git is killed, letting the readers finish.
+                        kill_child(&mut child);
+                        timed_out = true;
+                        break;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match line_rec.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+            };
+            // Progress updates are separated by carriage returns.
+            for part in String::from_utf8_lossy(&line).split('\r') {
+                let part = part.trim_end();
+                if !part.is_empty() {
+                    on_line(part);
+                }
+            }
+            if is_stderr {
+                stderr.extend(line);
+            } else {
+                stdout.extend(line);
+            }
+        }
+    });
+
+    if timed_out {
+        return Err(timeout_err(&command));
+    }
+    let status = wait_child(&command, &mut child, deadline, flags.cancel.as_ref())?;
+
+    if status.success() {
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
     } else {
         Err(command_err(
             &command,
-            Some(String::from_utf8_lossy(&output.stderr).into()),
+            Some(String::from_utf8_lossy(&stderr).into()),
         ))
     }
 }
@@ -503,57 +4379,205 @@ fn git_command<S: AsRef<OsStr>, P: AsRef<Path>>(
 fn show_git_command<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
     path: P,
-    flags: &[String],
+    flags: &GitFlags,
     args: &[&str],
 ) -> Result<()> {
+    // With a timeout git is put in its own process group so it can be killed along with anything
+    // it started. The pager has to stay in the terminal's foreground group, so it is started here
+    // instead of by git.
+    let killable = flags.timeout.is_some() || flags.cancel.is_some();
+    let mut pager = if killable && io::stdout().is_terminal() {
+        spawn_pager(&git, path.as_ref(), flags)?
+    } else {
+        None
+    };
+
     let mut command = Command::new(git.as_ref());
     command
         .current_dir(path.as_ref())
-        .args(flags)
-        .args(args)
+        .args(flags.iter())
         .env("GIT_TERMINAL_PROMPT", "0");
+    if let Some(pager) = &mut pager {
+        command
+            .arg("--no-pager")
+            .stdout(pager.stdin.take().unwrap())
+            .env("GIT_PAGER_IN_USE", "true");
+    }
+    command.args(args);
+    flags.set_env(&mut command);
+    flags.own_group(&mut command);
 
     log_cmd(&command);
-    let status = command
+    let res = command
         .spawn()
-        .map_err(|e| command_err(&command, Some(e.to_string())))?
-        .wait()
-        .map_err(|e| command_err(&command, Some(e.to_string())))?;
+        .map_err(|e| command_err(&command, Some(e.to_string())))
+        .and_then(|mut child| {
+            // Closes our end of the pager's stdin so it sees the end of the output.
+            command.stdout(Stdio::inherit());
+            wait_child(
+                &command,
+                &mut child,
+                flags.timeout.map(|timeout| Instant::now() + timeout),
+                flags.cancel.as_ref(),
+            )
+        });
 
-    if status.success() {
+    if let Some(mut pager) = pager {
+        if res.is_err() {
+            // SIGTERM rather than SIGKILL so the pager can restore the terminal.
+            if let Ok(pid) = libc::pid_t::try_from(pager.id()) {
+                // SAFETY: kill has no memory safety requirements.
+                unsafe {
+                    libc::kill(pid, libc::SIGTERM);
+                }
+            }
+        }
+        let _ = pager.wait();
+    }
+
+    if res?.success() {
         Ok(())
     } else {
         Err(command_err(&command, None))
     }
 }
 
+/// Starts the pager git would use, reading from a pipe. Returns `None` if git would not page.
+fn spawn_pager<S: AsRef<OsStr>>(git: S, path: &Path, flags: &GitFlags) -> Result<Option<Child>> {
+    let output = git_command(&git, path, flags, &["var", "GIT_PAGER"])?;
+    let pager = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if pager.is_empty() || pager == "cat" {
+        return Ok(None);
+    }
+
+    let mut command = Command::new("sh");
+    command
+        .current_dir(path)
+        .arg("-c")
+        .arg(&pager)
+        .stdin(Stdio::piped());
+    // The same defaults git starts its pager with.
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    if env::var_os("LV").is_none() {
+        command.env("LV", "-c");
+    }
+
+    log_cmd(&command);
+    command
+        .spawn()
+        .map(Some)
+        .map_err(|e| command_err(&command, Some(e.to_string())))
+}
+
 fn git_mark_seen<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
-    flags: &[String],
+    flags: &GitFlags,
     path: P,
+    seen: &str,
+    rev: &str,
 ) -> Result<Output> {
-    git_command(&git, &path, flags, &["update-ref", SEEN, "HEAD"])
+    git_command(&git, &path, flags, &["update-ref", seen, rev])
+}
+
+/// Checks if two commits share any history.
+fn git_is_related<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    a: &str,
+    b: &str,
+) -> Result<bool> {
+    let output = git_command_allow(git, path, flags, &["merge-base", a, b], &[1])?;
+    Ok(output.status.success())
+}
+
+fn git_reset_upstream<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+) -> Result<Output> {
+    let _ = git_command(&git, &path, flags, &["rebase", "--abort"]);
+    if git_command(&git, &path, flags, &["symbolic-ref", "-q", "HEAD"]).is_err() {
+        git_command(&git, &path, flags, &["checkout", "-f", "master"])?;
+    }
+    git_command(&git, &path, flags, &["reset", "--hard", "-q", "HEAD@{u}"])
 }
 
 fn git_rebase<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
-    flags: &[String],
+    flags: &GitFlags,
     path: P,
+    autostash: bool,
+    on_line: &mut dyn FnMut(&str),
 ) -> Result<Output> {
-    git_command(&git, &path, flags, &["reset", "--hard", "-q", "HEAD"])?;
+    if !autostash {
+        git_command(&git, &path, flags, &["reset", "--hard", "-q", "HEAD"])?;
+    }
     if git_command(&git, &path, flags, &["symbolic-ref", "-q", "HEAD"]).is_err() {
         git_command(&git, &path, flags, &["checkout", "master"])?;
     }
-    git_command(&git, &path, flags, &["rebase", "--stat"])
+    if autostash {
+        git_command_lines(
+            &git,
+            &path,
+            flags,
+            &["rebase", "--autostash", "--stat"],
+            on_line,
+        )
+    } else {
+        git_command_lines(&git, &path, flags, &["rebase", "--stat"], on_line)
+    }
+}
+
+fn git_rebase_in_progress<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+) -> Result<bool> {
+    for dir in ["rebase-merge", "rebase-apply"] {
+        let output = git_command(&git, &path, flags, &["rev-parse", "--git-path", dir])?;
+        let dir = path
+            .as_ref()
+            .join(String::from_utf8_lossy(&output.stdout).trim());
+        if dir.is_dir() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn git_conflicts<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+) -> Result<Vec<String>> {
+    let output = git_command(
+        git,
+        path,
+        flags,
+        &["diff", "--name-only", "--diff-filter=U"],
+    )?;
+    let files = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|f| f.to_string())
+        .collect();
+    Ok(files)
 }
 
-fn git_unseen<S: AsRef<OsStr>, P: AsRef<Path>>(git: S, flags: &[String], path: P) -> Result<bool> {
-    if git_has_seen(&git, flags, &path)? {
+fn git_unseen<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    seen: &str,
+) -> Result<bool> {
+    if git_has_seen(&git, flags, &path, seen)? {
         let is_unseen = git_command(
             git,
             path,
             flags,
-            &["merge-base", "--is-ancestor", "HEAD@{u}", "AUR_SEEN"],
+            &["merge-base", "--is-ancestor", "HEAD@{u}", seen],
         )
         .is_err();
         Ok(is_unseen)
@@ -564,11 +4588,12 @@ fn git_unseen<S: AsRef<OsStr>, P: AsRef<Path>>(git: S, flags: &[String], path: P
 
 fn git_has_diff<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
-    flags: &[String],
+    flags: &GitFlags,
     path: P,
+    seen: &str,
 ) -> Result<bool> {
-    if git_has_seen(&git, flags, &path)? {
-        let output = git_command(git, path, flags, &["rev-parse", SEEN, "HEAD@{u}"])?;
+    if git_has_seen(&git, flags, &path, seen)? {
+        let output = git_command(git, path, flags, &["rev-parse", seen, "HEAD@{u}"])?;
 
         let s = String::from_utf8_lossy(&output.stdout);
         let mut s = s.split('\n');
@@ -582,173 +4607,458 @@ fn git_has_diff<S: AsRef<OsStr>, P: AsRef<Path>>(
     }
 }
 
+fn git_ahead_behind<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+) -> Result<(usize, usize)> {
+    let output = git_command(
+        git,
+        path,
+        flags,
+        &["rev-list", "--left-right", "--count", "HEAD...HEAD@{u}"],
+    )?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut counts = output.split_whitespace().map(|n| n.parse().unwrap_or(0));
+    let ahead = counts.next().unwrap_or(0);
+    let behind = counts.next().unwrap_or(0);
+    Ok((ahead, behind))
+}
+
+fn git_is_dirty<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+) -> Result<bool> {
+    let output = git_command(git, path, flags, &["status", "--porcelain", "-uno"])?;
+    Ok(!output.stdout.is_empty())
+}
+
+fn git_show_file<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    rev: &str,
+    file: &str,
+) -> Result<Option<Vec<u8>>> {
+    let object = format!("{}:{}", rev, file);
+    if git_command(&git, &path, flags, &["cat-file", "-e", &object]).is_err() {
+        return Ok(None);
+    }
+    let output = git_command(&git, &path, flags, &["cat-file", "blob", &object])?;
+    Ok(Some(output.stdout))
+}
+
 fn git_log<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
-    flags: &[String],
+    flags: &GitFlags,
     path: P,
-    color: bool,
-) -> Result<Output> {
+    color: Color,
+    format: DiffFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
     let color = color_str(color);
-    git_command(git, path, flags, &["log", "..HEAD@{u}", color])
+    let mut args = vec!["log", "..HEAD@{u}", color];
+    // Only the subjects are wanted alongside just the diffstat.
+    if format == DiffFormat::Stat {
+        args.push("--oneline");
+    }
+    git_command_to(git, path, flags, &args, out)
+}
+
+fn git_is_shallow<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+) -> Result<bool> {
+    let output = git_command(git, path, flags, &["rev-parse", "--is-shallow-repository"])?;
+    Ok(output.stdout.starts_with(b"true"))
 }
 
 fn git_has_seen<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
-    flags: &[String],
+    flags: &GitFlags,
     path: P,
+    seen: &str,
 ) -> Result<bool> {
-    let output = git_command(&git, &path, flags, &["rev-parse", "--verify", SEEN]).is_ok();
+    let output = git_command(&git, &path, flags, &["rev-parse", "--verify", seen]).is_ok();
     Ok(output)
 }
 
-fn git_head<S: AsRef<OsStr>, P: AsRef<Path>>(git: S, flags: &[String], path: P) -> Result<String> {
-    let output = git_command(git, path, flags, &["rev-parse", "HEAD"])?;
+fn git_head<S: AsRef<OsStr>, P: AsRef<Path>>(git: S, flags: &GitFlags, path: P) -> Result<String> {
+    git_rev_parse(git, flags, path, "HEAD")
+}
+
+fn git_rev_parse<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    rev: &str,
+) -> Result<String> {
+    let output = git_command(git, path, flags, &["rev-parse", rev])?;
     let output = String::from_utf8_lossy(&output.stdout);
     Ok(output.trim().to_string())
 }
 
-fn git_diff<S: AsRef<OsStr>, P: AsRef<Path>>(
+fn git_hash_object<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
-    flags: &[String],
+    flags: &GitFlags,
     path: P,
-    color: bool,
-) -> Result<Output> {
-    let color = color_str(color);
-    let head = git_head(&git, flags, &path)?;
-    let output = if git_has_seen(&git, flags, &path)? {
-        git_command(&git, &path, flags, &["reset", "--hard", SEEN])?;
-        git_command(
-            &git,
-            &path,
-            flags,
-            &[
-                "-c",
-                "user.email=aur",
-                "-c",
-                "user.name=aur",
-                "merge",
-                "--no-edit",
-                "--no-ff",
-                "--no-commit",
-            ],
-        )?;
-        Ok(git_command(
-            &git,
-            &path,
-            flags,
-            &[
-                "diff",
-                "--stat",
-                "--patch",
-                "--cached",
-                color,
-                "--",
-                ":!.SRCINFO",
-            ],
-        )?)
-    } else {
-        Ok(git_command(
+    data: &[u8],
+) -> Result<String> {
+    let mut command = Command::new(git.as_ref());
+    command
+        .current_dir(path.as_ref())
+        .args(flags.iter())
+        .args(["hash-object", "--stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    flags.set_env(&mut command);
+    flags.own_group(&mut command);
+
+    log_cmd(&command);
+    let mut child = command
+        .spawn()
+        .map_err(|e| command_err(&command, Some(e.to_string())))?;
+    // Written on its own thread so a git that stops reading is still killed on timeout, which
+    // also closes the pipe and ends the write.
+    let mut stdin = child.stdin.take().unwrap();
+    let data = data.to_vec();
+    let write = thread::spawn(move || stdin.write_all(&data));
+    let output = wait_output(&command, child, flags.timeout, flags.cancel.as_ref())?;
+    let written = write
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("failed to write command input")));
+
+    if !output.status.success() {
+        return Err(command_err(
+            &command,
+            Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+        ));
+    }
+    written.map_err(|e| command_err(&command, Some(e.to_string())))?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn git_commit_time<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    rev: &str,
+) -> Result<Option<SystemTime>> {
+    let output = git_command(git, path, flags, &["log", "-1", "--format=%ct", rev])?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let time = output
+        .trim()
+        .parse()
+        .ok()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    Ok(time)
+}
+
+fn git_merge_tree<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    seen: &str,
+    cache: &MergeCache,
+    method: MergeMethod,
+) -> Result<String> {
+    let output = git_command(&git, &path, flags, &["rev-parse", seen, "HEAD@{u}"])?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut lines = output.lines();
+    let seen = lines.next().unwrap_or_default().to_string();
+    let upstream = lines.next().unwrap_or_default().to_string();
+    let key = (seen, upstream);
+
+    if let Some(tree) = cache.get(&key) {
+        // The tree may have been pruned since, or may have been computed in another repo.
+        let exists = git_command_allow(&git, &path, flags, &["cat-file", "-e", &tree], &[1])?;
+        if exists.status.success() {
+            return Ok(tree);
+        }
+        cache.remove(&key);
+    }
+
+    let tree = match method {
+        MergeMethod::Reset => {
+            let head = git_head(&git, flags, &path)?;
+            let tree = git_merge_write_tree(&git, flags, &path, &key.0, &key.1);
+            git_command(&git, &path, flags, &["reset", "--hard", &head])?;
+            tree
+        }
+        MergeMethod::Worktree => {
+            let worktree = git_worktree(&git, flags, &path)?;
+            git_merge_write_tree(&git, flags, &worktree.path, &key.0, &key.1)
+        }
+        // Exits 1 on conflicts but still writes a tree containing conflict markers.
+        MergeMethod::MergeTree => git_command_allow(
             &git,
             &path,
             flags,
-            &[
-                "diff",
-                "--stat",
-                "--patch",
-                color,
-                "4b825dc642cb6eb9a060e54bf8d69288fbee4904..HEAD@{u}",
-                "--",
-                ":!.SRCINFO",
-            ],
-        )?)
+            &["merge-tree", "--write-tree", &key.0, &key.1],
+            &[1],
+        ),
     };
 
-    git_command(&git, &path, flags, &["reset", "--hard", &head])?;
-    output
+    let output = tree?;
+    let tree = String::from_utf8_lossy(&output.stdout);
+    let tree = tree.lines().next().unwrap_or_default().to_string();
+    // A tree with conflict markers is only wanted by this call, so is never reused.
+    if output.status.success() {
+        cache.insert(key, tree.clone());
+    }
+    Ok(tree)
+}
+
+/// Resets the working tree to `seen` and merges `upstream` into it without committing,
+/// returning the tree of the result.
+///
+/// Commits are passed by hash as AUR_SEEN is not visible from other worktrees.
+fn git_merge_write_tree<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    seen: &str,
+    upstream: &str,
+) -> Result<Output> {
+    git_command(&git, &path, flags, &["reset", "--hard", seen])?;
+    git_command(
+        &git,
+        &path,
+        flags,
+        &[
+            "-c",
+            "user.email=aur",
+            "-c",
+            "user.name=aur",
+            "merge",
+            "--no-edit",
+            "--no-ff",
+            "--no-commit",
+            upstream,
+        ],
+    )
+    .and_then(|_| git_command(&git, &path, flags, &["write-tree"]))
+}
+
+/// A scratch worktree of a package, locked so that nothing else merges in it while held.
+struct Worktree {
+    path: PathBuf,
+    _lock: File,
 }
 
-fn show_git_diff<S: AsRef<OsStr>, P: AsRef<Path>>(git: S, flags: &[String], path: P) -> Result<()> {
-    let head = git_head(&git, flags, &path)?;
-    if git_has_seen(&git, flags, &path)? {
-        git_command(&git, &path, flags, &["reset", "--hard", SEEN])?;
+/// Takes a free scratch worktree of the package, creating one if they are all in use.
+///
+/// The worktrees are kept inside the package's git dir and reused between calls. Each is locked
+/// with `flock` on a file beside it, which is released when the process exits even if it
+/// crashes, so concurrent diffs of the same package never share a worktree.
+fn git_worktree<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+) -> Result<Worktree> {
+    let git_dir = git_command(&git, &path, flags, &["rev-parse", "--absolute-git-dir"])?;
+    let git_dir = PathBuf::from(String::from_utf8_lossy(&git_dir.stdout).trim());
+    let mut n = 0;
+
+    let (worktree, lock) = loop {
+        let name = match n {
+            0 => "aur-fetch-worktree".to_string(),
+            n => format!("aur-fetch-worktree-{}", n),
+        };
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(git_dir.join(format!("{}.lock", name)))?;
+        if try_lock(&lock)? {
+            break (git_dir.join(name), lock);
+        }
+        n += 1;
+    };
+
+    if !worktree.join(".git").is_file() {
+        git_command(&git, &path, flags, &["worktree", "prune"])?;
+        let worktree = worktree.to_string_lossy();
         git_command(
             &git,
             &path,
             flags,
             &[
-                "-c",
-                "user.email=aur",
-                "-c",
-                "user.name=aur",
-                "merge",
-                "--no-edit",
-                "--no-ff",
-                "--no-commit",
+                "worktree", "add", "-q", "-f", "--detach", &worktree, "HEAD@{u}",
             ],
         )?;
-        show_git_command(
-            &git,
-            &path,
-            flags,
-            &["diff", "--stat", "--patch", "--cached", "--", ":!.SRCINFO"],
-        )?;
+    }
+
+    Ok(Worktree {
+        path: worktree,
+        _lock: lock,
+    })
+}
+
+/// Takes an exclusive lock on a file without blocking, returning false if it is already held.
+fn try_lock(file: &File) -> io::Result<bool> {
+    // SAFETY: the fd stays open for as long as file is borrowed.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
     } else {
-        show_git_command(
-            &git,
-            &path,
-            flags,
-            &[
-                "diff",
-                "--stat",
-                "--patch",
-                "4b825dc642cb6eb9a060e54bf8d69288fbee4904..HEAD@{u}",
-                "--",
-                ":!.SRCINFO",
-            ],
-        )?;
+        Err(err)
     }
+}
 
-    git_command(&git, &path, flags, &["reset", "--hard", &head])?;
-    Ok(())
+/// Builds the args for `git diff`.
+///
+/// `range` is the revs to diff followed by `--` and the paths to diff.
+fn git_diff_args(range: &[String], diff_flags: &[String], format: DiffFormat) -> Vec<String> {
+    let mut args = vec!["diff".to_string()];
+    if format != DiffFormat::Patch {
+        args.push("--stat".into());
+    }
+    if format != DiffFormat::Stat {
+        args.push("--patch".into());
+    }
+    args.extend(diff_flags.iter().cloned());
+    args.extend(range.iter().cloned());
+    args
+}
+
+fn git_diff_changed<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    range: &[String],
+) -> Result<bool> {
+    let mut args = vec!["diff", "--quiet"];
+    args.extend(range.iter().map(|s| s.as_str()));
+    let output = git_command_allow(git, path, flags, &args, &[1])?;
+    Ok(!output.status.success())
+}
+
+fn git_diff_size<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    range: &[String],
+) -> Result<usize> {
+    let mut args = vec!["diff".to_string(), "--numstat".into()];
+    args.extend(range.iter().cloned());
+    let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let output = git_command(&git, &path, flags, &args)?;
+
+    // Binary files show - instead of a line count so are counted as 0.
+    let size = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .flat_map(|line| line.split('\t').take(2))
+        .filter_map(|n| n.parse::<usize>().ok())
+        .sum();
+    Ok(size)
+}
+
+fn git_diff<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    args: &[String],
+    color: Color,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let mut args = args.to_vec();
+    args.insert(1, color_str(color).into());
+    let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    git_command_to(&git, &path, flags, &args, out)
+}
+
+/// Diffs the untracked files against nothing, as if they were newly added.
+///
+/// The files are added with `git add -N` to a scratch copy of the index so that one diff shows
+/// them all without touching the real index.
+fn git_diff_untracked<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    args: &[String],
+    color: Color,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let path = path.as_ref();
+    let index = git_command(&git, path, flags, &["rev-parse", "--git-path", "index"])?;
+    let index = path.join(String::from_utf8_lossy(&index.stdout).trim());
+    let scratch = index.with_file_name("aur-fetch-untracked-index");
+
+    match fs::copy(&index, &scratch) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+
+    let mut flags = flags.clone();
+    flags
+        .env
+        .push(("GIT_INDEX_FILE".into(), scratch.clone().into()));
+    let res = git_command(&git, path, &flags, &["add", "-N", "."])
+        .and_then(|_| git_diff(&git, &flags, path, args, color, out));
+
+    let _ = fs::remove_file(&scratch);
+    res
+}
+
+fn show_git_diff<S: AsRef<OsStr>, P: AsRef<Path>>(
+    git: S,
+    flags: &GitFlags,
+    path: P,
+    range: &[String],
+    diff_flags: &[String],
+) -> Result<()> {
+    let args = git_diff_args(range, diff_flags, DiffFormat::StatAndPatch);
+    let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    show_git_command(&git, &path, flags, &args)
 }
 
 fn git_commit<S: AsRef<OsStr>, P: AsRef<Path>>(
     git: S,
-    flags: &[String],
+    flags: &GitFlags,
     path: P,
     message: &str,
-) -> Result<()> {
+) -> Result<bool> {
     let path = path.as_ref();
     let git = git.as_ref();
 
     let has_user = git_command(git, path, flags, &["config", "user.name"]).is_ok()
         && git_command(git, path, flags, &["config", "user.email"]).is_ok();
 
-    if git_command(git, path, flags, &["diff", "--exit-code"]).is_err() {
-        if has_user {
-            git_command(git, path, flags, &["commit", "-am", message])?;
-        } else {
-            git_command(
-                git,
-                path,
-                flags,
-                &[
-                    "-c",
-                    "user.email=aur",
-                    "-c",
-                    "user.name=aur",
-                    "commit",
-                    "-am",
-                    "AUR",
-                ],
-            )?;
-        }
+    if git_command(git, path, flags, &["diff", "--exit-code"]).is_ok() {
+        return Ok(false);
     }
 
-    Ok(())
+    if has_user {
+        git_command(git, path, flags, &["commit", "-am", message])?;
+    } else {
+        git_command(
+            git,
+            path,
+            flags,
+            &[
+                "-c",
+                "user.email=aur",
+                "-c",
+                "user.name=aur",
+                "commit",
+                "-am",
+                "AUR",
+            ],
+        )?;
+    }
+
+    Ok(true)
 }
 
-fn log_cmd(cmd: &Command) {
+pub(crate) fn log_cmd(cmd: &Command) {
     if log::log_enabled!(log::Level::Debug) {
         let bin = cmd.get_program().to_string_lossy().to_string();
         let args = cmd
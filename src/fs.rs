@@ -0,0 +1,132 @@
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix;
+use std::path::{Path, PathBuf};
+
+/// The filesystem operations used for the files [`Fetch`](struct.Fetch.html) manages itself.
+///
+/// This covers everything in the diff dir, such as saved diffs, their history and recorded
+/// provenance, along with views and download manifests. This allows those files to be
+/// intercepted, for example to keep them in memory in tests.
+///
+/// Package repos in the clone dir belong to git, which always operates on the real filesystem,
+/// so the clone dir and everything in it is always accessed directly.
+pub trait FileSystem: Debug + Send + Sync {
+    /// Recursively create a directory and all of its parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Create a symlink at `dest` pointing to `src`.
+    fn symlink(&self, src: &Path, dest: &Path) -> io::Result<()>;
+    /// Write `data` to a file, replacing it if it already exists.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+    /// Append `data` to a file, creating it if it does not exist.
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+    /// Read the contents of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// List the paths of the entries in a directory.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Read the target of a symlink.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Check if a path is a file, following symlinks.
+    fn is_file(&self, path: &Path) -> bool;
+    /// Check if a path exists without following symlinks.
+    fn exists(&self, path: &Path) -> bool;
+    /// Remove a file or symlink.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    /// Recursively remove a directory.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Move a file or directory.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Open a file for writing, replacing it if it already exists.
+    ///
+    /// By default the file is written through [`write`](#tymethod.write) and
+    /// [`append`](#tymethod.append).
+    fn create<'a>(&'a self, path: &Path) -> io::Result<Box<dyn Write + 'a>> {
+        self.write(path, &[])?;
+        Ok(Box::new(Appender {
+            fs: self,
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+/// Writes to a file by appending to it through a [`FileSystem`].
+struct Appender<'a, F: ?Sized> {
+    fs: &'a F,
+    path: PathBuf,
+}
+
+impl<F: FileSystem + ?Sized> Write for Appender<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.fs.append(&self.path, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The real filesystem.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn symlink(&self, src: &Path, dest: &Path) -> io::Result<()> {
+        unix::fs::symlink(src, dest)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        fs::write(path, data)
+    }
+
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(data)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.symlink_metadata().is_ok()
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn create<'a>(&'a self, path: &Path) -> io::Result<Box<dyn Write + 'a>> {
+        Ok(Box::new(File::create(path)?))
+    }
+}
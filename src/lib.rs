@@ -104,10 +104,22 @@
 //! # }
 //! ```
 #![warn(missing_docs)]
+mod builder;
 mod callback;
+#[cfg(feature = "tokio")]
+mod download_async;
 mod error;
 mod fetch;
+mod fs;
+mod provenance;
+mod ref_update;
+#[cfg(feature = "snapshots")]
+mod snapshot;
 
+pub use builder::*;
 pub use callback::*;
 pub use error::*;
 pub use fetch::*;
+pub use fs::*;
+pub use provenance::*;
+pub use ref_update::*;
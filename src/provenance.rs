@@ -0,0 +1,107 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where and when a package was downloaded.
+///
+/// Recorded after each download when
+/// [`record_provenance`](struct.Fetch.html#structfield.record_provenance) is set and read back
+/// with [`Fetch::provenance`](struct.Fetch.html#method.provenance).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Provenance {
+    /// The URL the package was downloaded from.
+    pub url: String,
+    /// When the download finished.
+    pub time: SystemTime,
+    /// The upstream commit the package was at after downloading.
+    pub commit: String,
+}
+
+impl Provenance {
+    pub(crate) fn to_json(&self) -> String {
+        let secs = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!(
+            "{{\n  \"url\": {},\n  \"timestamp\": {},\n  \"commit\": {}\n}}\n",
+            json_str(&self.url),
+            secs,
+            json_str(&self.commit)
+        )
+    }
+
+    /// Parses the output of `to_json`. This is not a general JSON parser and only handles one
+    /// field per line.
+    pub(crate) fn from_json(json: &str) -> Option<Provenance> {
+        let mut url = None;
+        let mut time = None;
+        let mut commit = None;
+
+        for line in json.lines() {
+            let line = line.trim().trim_end_matches(',');
+            let (key, value) = match line.split_once(':') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let value = value.trim();
+
+            match key.trim() {
+                "\"url\"" => url = json_unescape(value),
+                "\"timestamp\"" => {
+                    time = value
+                        .parse()
+                        .ok()
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+                }
+                "\"commit\"" => commit = json_unescape(value),
+                _ => (),
+            }
+        }
+
+        Some(Provenance {
+            url: url?,
+            time: time?,
+            commit: commit?,
+        })
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if c.is_control() => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+fn json_unescape(s: &str) -> Option<String> {
+    let s = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'u' => {
+                let hex = chars.by_ref().take(4).collect::<String>();
+                ret.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            'n' => ret.push('\n'),
+            't' => ret.push('\t'),
+            'r' => ret.push('\r'),
+            c => ret.push(c),
+        }
+    }
+
+    Some(ret)
+}
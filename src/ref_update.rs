@@ -0,0 +1,164 @@
+/// How a ref was changed by a fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefUpdateKind {
+    /// The ref was fast forwarded.
+    FastForward,
+    /// The ref was force updated.
+    Forced,
+    /// The ref did not exist before.
+    New,
+    /// The ref was deleted.
+    Deleted,
+    /// A tag was updated.
+    Tag,
+    /// The ref was not updated.
+    Rejected,
+    /// The ref was already up to date.
+    UpToDate,
+}
+
+/// A ref that was changed by a fetch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    /// The name of the local ref that was updated.
+    pub ref_name: String,
+    /// The commit the ref pointed to before the fetch, if git reported it.
+    pub old: Option<String>,
+    /// The commit the ref points to after the fetch, if git reported it.
+    pub new: Option<String>,
+    /// How the ref was changed.
+    pub kind: RefUpdateKind,
+}
+
+impl RefUpdate {
+    /// Parses the ref updates out of the output of `git fetch -v`.
+    ///
+    /// Lines that do not describe a ref update are ignored.
+    pub fn parse(output: &str) -> Vec<RefUpdate> {
+        output.lines().filter_map(RefUpdate::parse_line).collect()
+    }
+
+    fn parse_line(line: &str) -> Option<RefUpdate> {
+        let line = line.strip_prefix(' ')?;
+        let mut chars = line.chars();
+
+        let kind = match chars.next()? {
+            ' ' => RefUpdateKind::FastForward,
+            '+' => RefUpdateKind::Forced,
+            '*' => RefUpdateKind::New,
+            '-' => RefUpdateKind::Deleted,
+            't' => RefUpdateKind::Tag,
+            '!' => RefUpdateKind::Rejected,
+            '=' => RefUpdateKind::UpToDate,
+            _ => return None,
+        };
+
+        let rest = chars.as_str().strip_prefix(' ')?.trim_start();
+        let (summary, rest) = if rest.starts_with('[') {
+            let end = rest.find(']')?;
+            (&rest[..=end], &rest[end + 1..])
+        } else {
+            rest.split_once(char::is_whitespace)?
+        };
+
+        let (_, to) = rest.split_once(" -> ")?;
+        let ref_name = to.split_whitespace().next()?.to_string();
+
+        let (old, new) = match summary
+            .split_once("...")
+            .or_else(|| summary.split_once(".."))
+        {
+            Some((old, new)) => (Some(old.to_string()), Some(new.to_string())),
+            None => (None, None),
+        };
+
+        Some(RefUpdate {
+            ref_name,
+            old,
+            new,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(
+        ref_name: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+        kind: RefUpdateKind,
+    ) -> RefUpdate {
+        RefUpdate {
+            ref_name: ref_name.to_string(),
+            old: old.map(str::to_string),
+            new: new.map(str::to_string),
+            kind,
+        }
+    }
+
+    #[test]
+    fn fast_forward() {
+        assert_eq!(
+            RefUpdate::parse("   1a2b3c4..5d6e7f8  master     -> origin/master\n"),
+            [update(
+                "origin/master",
+                Some("1a2b3c4"),
+                Some("5d6e7f8"),
+                RefUpdateKind::FastForward
+            )]
+        );
+    }
+
+    #[test]
+    fn forced() {
+        assert_eq!(
+            RefUpdate::parse(" + 1a2b3c4...5d6e7f8 master     -> origin/master  (forced update)"),
+            [update(
+                "origin/master",
+                Some("1a2b3c4"),
+                Some("5d6e7f8"),
+                RefUpdateKind::Forced
+            )]
+        );
+    }
+
+    #[test]
+    fn rejected() {
+        assert_eq!(
+            RefUpdate::parse(
+                " ! [rejected]        master     -> origin/master  (non-fast-forward)"
+            ),
+            [update("origin/master", None, None, RefUpdateKind::Rejected)]
+        );
+    }
+
+    #[test]
+    fn new_tag() {
+        assert_eq!(
+            RefUpdate::parse(" * [new tag]         v1         -> v1"),
+            [update("v1", None, None, RefUpdateKind::New)]
+        );
+    }
+
+    #[test]
+    fn pruned() {
+        assert_eq!(
+            RefUpdate::parse(" - [deleted]         (none)     -> origin/old"),
+            [update("origin/old", None, None, RefUpdateKind::Deleted)]
+        );
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        let output = "From https://aur.archlinux.org/foo\n \
+                      = [up to date]      master     -> origin/master\n\
+                      POST git-upload-pack (165 bytes)\n";
+        assert_eq!(
+            RefUpdate::parse(output),
+            [update("origin/master", None, None, RefUpdateKind::UpToDate)]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use crate::fetch::validate_name;
+use crate::{Fetch, Result};
+
+use std::fs::{create_dir_all, remove_dir_all, rename};
+use std::io;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use url::Url;
+
+impl Fetch {
+    /// Downloads a list of packages as snapshot tarballs instead of cloning them.
+    ///
+    /// Each package's snapshot is downloaded from `<aur_url>/cgit/aur.git/snapshot/<pkg>.tar.gz`
+    /// and extracted to `<clone_dir>/<pkg>`, replacing any previously downloaded snapshot.
+    /// This is quicker than cloning when only the build files are needed.
+    ///
+    /// Snapshots are plain directories, not git repos. They have no history, so diffing,
+    /// merging and marking packages as seen do not work on them. A package that has already been
+    /// cloned is refused rather than overwritten.
+    ///
+    /// [`command_timeout`](#structfield.command_timeout) limits how long each request may take
+    /// and requests go through [`proxy`](#structfield.proxy) if it is set.
+    pub fn download_snapshots<S: AsRef<str>>(&self, pkgs: &[S]) -> Result<()> {
+        pkgs.iter()
+            .try_for_each(|pkg| validate_name(pkg.as_ref()))?;
+        self.mk_clone_dir()?;
+
+        let mut agent = ureq::AgentBuilder::new();
+        if let Some(timeout) = self.command_timeout {
+            agent = agent.timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            agent = agent.proxy(ureq::Proxy::new(proxy).map_err(io::Error::other)?);
+        }
+        let agent = agent.build();
+
+        for pkg in pkgs {
+            let pkg = pkg.as_ref();
+            let dest = self.pkg_dir(pkg)?;
+            if self.is_git_repo(pkg) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is a git repo", dest.display()),
+                )
+                .into());
+            }
+
+            let url = self.snapshot_url(pkg);
+            log::debug!("GET {}", url);
+            let response = agent
+                .request_url("GET", &url)
+                .call()
+                .map_err(io::Error::other)?;
+
+            // Extract next to the destination so a failed download leaves the old snapshot alone.
+            let tmp = self.clone_dir.join(format!(".{}.snapshot", pkg));
+            remove_dir_if_exists(&tmp)?;
+            create_dir_all(&tmp)?;
+            let res = (|| {
+                tar::Archive::new(GzDecoder::new(response.into_reader())).unpack(&tmp)?;
+                let extracted = tmp.join(pkg);
+                if !extracted.is_dir() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("snapshot of {} does not contain {}/", pkg, pkg),
+                    ));
+                }
+                remove_dir_if_exists(&dest)?;
+                rename(extracted, &dest)
+            })();
+            remove_dir_if_exists(&tmp)?;
+            res?;
+        }
+
+        Ok(())
+    }
+
+    fn snapshot_url(&self, pkg: &str) -> Url {
+        let mut url = self.aur_url.clone();
+        let path = format!(
+            "{}/cgit/aur.git/snapshot/{}.tar.gz",
+            url.path().trim_end_matches('/'),
+            pkg
+        );
+        url.set_path(&path);
+        url
+    }
+}
+
+fn remove_dir_if_exists(path: &Path) -> io::Result<()> {
+    match remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aur_fetch::{
+    CancelToken, ConflictPolicy, DiffMode, Fetch, LockPolicy, RealFs, UnrelatedPolicy,
+};
+
+#[test]
+fn builder_matches_fields() {
+    let cancel = CancelToken::default();
+    let built = Fetch::builder()
+        .cache_dir("/cache")
+        .git("/usr/local/bin/git")
+        .git_flags(["--no-pager"])
+        .aur_url("https://example.com/aur/".parse().unwrap())
+        .diff_algorithm("histogram")
+        .find_renames(60)
+        .find_copies(true)
+        .diff_submodule("log")
+        .max_diff_bytes(1024)
+        .diff_preview_lines(20)
+        .diff_untracked(true)
+        .diff_exclude([".SRCINFO", "*.lock"])
+        .srcinfo_fallback(true)
+        .saved_diff_stat(false)
+        .keep_diff_history(true)
+        .http_headers(["X-Test: 1"])
+        .proxy("http://proxy:8080")
+        .protocol_version(2)
+        .batch_timeout(Duration::from_secs(60))
+        .cancel(cancel.clone())
+        .command_timeout(Duration::from_secs(10))
+        .retries(3)
+        .retry_delay(Duration::from_millis(100))
+        .clone_jobs(8)
+        .seen_ref("refs/mytool/seen")
+        .diff_mode(DiffMode::Range)
+        .use_worktrees(true)
+        .performance_config(true)
+        .clone_into_existing(true)
+        .single_branch(true)
+        .clone_args(["--filter=blob:none"])
+        .clean_keep(["*.pkg.tar.zst"])
+        .clone_depth(1)
+        .alternates(["/objects"])
+        .prune(true)
+        .verify_command(["namcap", "PKGBUILD"])
+        .record_provenance(true)
+        .merge_autostash(true)
+        .merge_jobs(4)
+        .worker_name("worker")
+        .worker_stack_size(1 << 20)
+        .conflict_policy(ConflictPolicy::Theirs)
+        .unrelated_policy(UnrelatedPolicy::Reset)
+        .lock_policy(LockPolicy::Wait(Duration::from_secs(5)))
+        .fs(Arc::new(RealFs))
+        .build()
+        .unwrap();
+
+    let mut fetch = Fetch::with_cache_dir("/cache");
+    fetch.git = "/usr/local/bin/git".into();
+    fetch.git_flags = vec!["--no-pager".into()];
+    fetch.aur_url = "https://example.com/aur/".parse().unwrap();
+    fetch.diff_algorithm = Some("histogram".into());
+    fetch.find_renames = Some(60);
+    fetch.find_copies = true;
+    fetch.diff_submodule = Some("log".into());
+    fetch.max_diff_bytes = Some(1024);
+    fetch.diff_preview_lines = Some(20);
+    fetch.diff_untracked = true;
+    fetch.diff_exclude = vec![".SRCINFO".into(), "*.lock".into()];
+    fetch.srcinfo_fallback = true;
+    fetch.saved_diff_stat = false;
+    fetch.keep_diff_history = true;
+    fetch.http_headers = vec!["X-Test: 1".into()];
+    fetch.proxy = Some("http://proxy:8080".into());
+    fetch.protocol_version = Some(2);
+    fetch.batch_timeout = Some(Duration::from_secs(60));
+    fetch.cancel = cancel;
+    fetch.command_timeout = Some(Duration::from_secs(10));
+    fetch.retries = 3;
+    fetch.retry_delay = Duration::from_millis(100);
+    fetch.clone_jobs = 8;
+    fetch.seen_ref = "refs/mytool/seen".into();
+    fetch.diff_mode = DiffMode::Range;
+    fetch.use_worktrees = true;
+    fetch.performance_config = true;
+    fetch.clone_into_existing = true;
+    fetch.single_branch = true;
+    fetch.clone_args = vec!["--filter=blob:none".into()];
+    fetch.clean_keep = vec!["*.pkg.tar.zst".into()];
+    fetch.clone_depth = Some(1);
+    fetch.alternates = vec![PathBuf::from("/objects")];
+    fetch.prune = true;
+    fetch.verify_command = Some(vec!["namcap".into(), "PKGBUILD".into()]);
+    fetch.record_provenance = true;
+    fetch.merge_autostash = true;
+    fetch.merge_jobs = 4;
+    fetch.worker_name = "worker".into();
+    fetch.worker_stack_size = Some(1 << 20);
+    fetch.conflict_policy = ConflictPolicy::Theirs;
+    fetch.unrelated_policy = UnrelatedPolicy::Reset;
+    fetch.lock_policy = LockPolicy::Wait(Duration::from_secs(5));
+    fetch.fs = Arc::new(RealFs);
+
+    assert_eq!(format!("{:?}", built), format!("{:?}", fetch));
+    // Unset options keep the defaults.
+    let default = Fetch::builder().cache_dir("/cache").build().unwrap();
+    assert_eq!(
+        format!("{:?}", default),
+        format!("{:?}", Fetch::with_cache_dir("/cache"))
+    );
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use std::fs;
+
+use common::Env;
+
+#[test]
+fn clean_untracked_removes_junk() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    env.push("foo", ".gitignore", "pkg/\n*.log\n", "ignore");
+    let mut fetch = env.fetch();
+    fetch.clean_keep = vec!["*.pkg.tar.zst".into()];
+    fetch.download(&["foo"]).unwrap();
+    fetch.save_diffs(&["foo"]).unwrap();
+    let diff = env.cache().join("diff").join("foo.diff");
+    let saved = fs::read_to_string(&diff).unwrap();
+
+    let clone = env.clone_dir("foo");
+    fs::create_dir_all(clone.join("pkg/foo")).unwrap();
+    fs::create_dir_all(clone.join("src/foo")).unwrap();
+    fs::write(clone.join("pkg/foo/file"), "ignored").unwrap();
+    fs::write(clone.join("src/foo/file"), "untracked").unwrap();
+    fs::write(clone.join("build.log"), "ignored").unwrap();
+    fs::write(clone.join("foo-1-1-any.pkg.tar.zst"), "kept").unwrap();
+    fs::write(clone.join("PKGBUILD"), "pkgname=local\n").unwrap();
+
+    fetch.clean_untracked(&["foo"]).unwrap();
+    assert!(!clone.join("pkg").exists());
+    assert!(!clone.join("src").exists());
+    assert!(!clone.join("build.log").exists());
+    assert!(clone.join("foo-1-1-any.pkg.tar.zst").exists());
+    // Tracked files and the diff dir are left alone.
+    assert_eq!(
+        fs::read_to_string(clone.join("PKGBUILD")).unwrap(),
+        "pkgname=local\n"
+    );
+    assert!(clone.join(".gitignore").exists());
+    assert_eq!(fs::read_to_string(&diff).unwrap(), saved);
+}
+
+#[test]
+fn clean_untracked_needs_a_repo() {
+    let env = Env::new();
+    let fetch = env.fetch();
+    fs::create_dir_all(env.clone_dir("foo")).unwrap();
+    fs::write(env.clone_dir("foo").join("file"), "").unwrap();
+
+    assert!(fetch.clean_untracked(&["foo"]).is_err());
+    assert!(fetch.clean_untracked(&["bar"]).is_err());
+    assert!(env.clone_dir("foo").join("file").exists());
+}
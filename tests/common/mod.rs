@@ -0,0 +1,121 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Once;
+
+use aur_fetch::Fetch;
+use tempfile::TempDir;
+use url::Url;
+
+/// A temporary cache dir along with local bare repos that act as the AUR.
+pub struct Env {
+    pub dir: TempDir,
+}
+
+impl Env {
+    pub fn new() -> Env {
+        static IDENTITY: Once = Once::new();
+        // Merging and committing need an identity and the tests should not depend on the user's.
+        IDENTITY.call_once(|| {
+            for var in ["GIT_AUTHOR_NAME", "GIT_COMMITTER_NAME"] {
+                std::env::set_var(var, "aur-fetch");
+            }
+            for var in ["GIT_AUTHOR_EMAIL", "GIT_COMMITTER_EMAIL"] {
+                std::env::set_var(var, "aur-fetch@localhost");
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("aur")).unwrap();
+        fs::create_dir(dir.path().join("work")).unwrap();
+        Env { dir }
+    }
+
+    /// A handle using this env's cache dir and AUR.
+    pub fn fetch(&self) -> Fetch {
+        let mut fetch = Fetch::with_cache_dir(self.cache());
+        fetch.aur_url = self.aur_url();
+        fetch
+    }
+
+    pub fn aur_url(&self) -> Url {
+        Url::from_directory_path(self.dir.path().join("aur")).unwrap()
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.dir.path().join("cache")
+    }
+
+    pub fn clone_dir(&self, pkg: &str) -> PathBuf {
+        self.cache().join("clone").join(pkg)
+    }
+
+    pub fn upstream(&self, pkg: &str) -> PathBuf {
+        self.dir.path().join("aur").join(pkg)
+    }
+
+    /// Creates a package upstream with a PKGBUILD and .SRCINFO.
+    pub fn add_pkg(&self, pkg: &str) {
+        git(
+            &self.dir.path().join("aur"),
+            &["init", "-q", "--bare", "-b", "master", pkg],
+        );
+        let work = self.work(pkg);
+        git(
+            &self.dir.path().join("work"),
+            &["init", "-q", "-b", "master", pkg],
+        );
+        git(
+            &work,
+            &[
+                "remote",
+                "add",
+                "origin",
+                self.upstream(pkg).to_str().unwrap(),
+            ],
+        );
+        self.push(pkg, "PKGBUILD", &format!("pkgname={}\n", pkg), "init");
+        self.push(pkg, ".SRCINFO", &format!("pkgbase = {}\n", pkg), "srcinfo");
+    }
+
+    /// Commits a file to a package upstream.
+    pub fn push(&self, pkg: &str, file: &str, contents: &str, msg: &str) {
+        let work = self.work(pkg);
+        fs::write(work.join(file), contents).unwrap();
+        git(&work, &["add", file]);
+        git(&work, &["commit", "-q", "-m", msg]);
+        git(&work, &["push", "-q", "origin", "HEAD:master"]);
+    }
+
+    pub fn work(&self, pkg: &str) -> PathBuf {
+        self.dir.path().join("work").join(pkg)
+    }
+}
+
+/// Runs git, panicking if it fails, and returns its trimmed stdout.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "git {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// Writes an executable script to the env's dir and returns its path.
+pub fn script(env: &Env, name: &str, contents: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = env.dir.path().join(name);
+    fs::write(&path, format!("#!/bin/sh\n{}", contents)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
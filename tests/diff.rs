@@ -0,0 +1,442 @@
+mod common;
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use aur_fetch::{Color, Fetch, MergeCache};
+use common::{git, Env};
+
+/// Downloads and marks foo as seen, then pushes a change to it.
+fn seen_then_changed(env: &Env) {
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+    fetch.merge(&["foo"]).unwrap();
+    fetch.mark_seen(&["foo"]).unwrap();
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=2\n", "update");
+    fetch.download(&["foo"]).unwrap();
+}
+
+#[test]
+fn handles_share_merges() {
+    let env = Env::new();
+    seen_then_changed(&env);
+
+    // Every field is public so handles can be built from another with struct update syntax.
+    let worktree = Fetch {
+        use_worktrees: true,
+        ..env.fetch()
+    };
+    let first = env.fetch().diff(&["foo"], Color::Never).unwrap();
+    let second = worktree.diff(&["foo"], Color::Never).unwrap();
+    let third = env.fetch().diff_safe(&["foo"], Color::Never).unwrap();
+
+    assert!(first[0].contains("+pkgver=2"), "{}", first[0]);
+    assert_eq!(first, second);
+    assert_eq!(first, third);
+}
+
+#[test]
+fn pruned_merges_are_recomputed() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    let clone = env.clone_dir("foo");
+    fetch.download(&["foo"]).unwrap();
+    fetch.merge(&["foo"]).unwrap();
+    // A local commit so that the merged tree differs from every commit's tree.
+    fs::write(clone.join("local"), "local\n").unwrap();
+    git(&clone, &["add", "local"]);
+    git(&clone, &["commit", "-q", "-m", "local"]);
+    fetch.mark_seen(&["foo"]).unwrap();
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=2\n", "update");
+    fetch.download(&["foo"]).unwrap();
+
+    let first = fetch.diff(&["foo"], Color::Never).unwrap();
+    assert!(first[0].contains("+pkgver=2"), "{}", first[0]);
+    // The merged tree is not referenced by anything so this removes it.
+    git(&clone, &["reflog", "expire", "--expire=now", "--all"]);
+    git(&clone, &["gc", "-q", "--prune=now"]);
+    let second = fetch.diff(&["foo"], Color::Never).unwrap();
+    assert_eq!(first, second);
+}
+
+#[test]
+fn merge_cache_keeps_the_newest_trees() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    env.add_pkg("bar");
+    let mut fetch = env.fetch();
+    fetch.download(&["bar"]).unwrap();
+    fetch.merge(&["bar"]).unwrap();
+    fetch.mark_seen(&["bar"]).unwrap();
+    env.push("bar", "PKGBUILD", "pkgname=bar\npkgver=2\n", "update");
+    fetch.download(&["bar"]).unwrap();
+
+    let log = env.dir.path().join("log");
+    fetch.git = common::script(
+        &env,
+        "logging-git",
+        &format!("echo \"$*\" >> '{}'\nexec git \"$@\"\n", log.display()),
+    );
+    fetch.merge_cache = MergeCache::with_capacity(1);
+
+    for pkg in ["foo", "foo", "bar", "foo"] {
+        fetch.diff(&[pkg], Color::Never).unwrap();
+    }
+    let log = fs::read_to_string(log).unwrap();
+    let merges = log.lines().filter(|l| l.ends_with("write-tree")).count();
+    // Diffing bar pushes foo out of the cache.
+    assert_eq!(merges, 3, "{}", log);
+}
+
+#[test]
+fn shallow_clones_diff_from_seen() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=1\n", "v1");
+    let mut fetch = env.fetch();
+    fetch.clone_depth = Some(1);
+    let clone = env.clone_dir("foo");
+
+    fetch.download(&["foo"]).unwrap();
+    assert_eq!(
+        git(&clone, &["rev-parse", "--is-shallow-repository"]),
+        "true"
+    );
+    let diff = fetch.diff(&["foo"], Color::Never).unwrap();
+    assert!(diff[0].contains("+pkgver=1"), "{}", diff[0]);
+    fetch.accept(&["foo"], true).unwrap();
+
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=2\n", "v2");
+    fetch.download(&["foo"]).unwrap();
+    let diff = fetch.diff(&["foo"], Color::Never).unwrap();
+    assert!(diff[0].contains("-pkgver=1"), "{}", diff[0]);
+    assert!(diff[0].contains("+pkgver=2"), "{}", diff[0]);
+    assert!(!diff[0].contains("+pkgname=foo"), "{}", diff[0]);
+
+    fetch.merge(&["foo"]).unwrap();
+    assert_eq!(
+        fs::read_to_string(clone.join("PKGBUILD")).unwrap(),
+        "pkgname=foo\npkgver=2\n"
+    );
+}
+
+#[test]
+fn diff_safe_keeps_local_changes() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let clone = env.clone_dir("foo");
+    fs::write(clone.join("PKGBUILD"), "pkgname=local\n").unwrap();
+    fs::write(clone.join("untracked"), "untracked\n").unwrap();
+    let status = git(&clone, &["status", "--porcelain"]);
+
+    let diff = env.fetch().diff_safe(&["foo"], Color::Never).unwrap();
+    assert!(diff[0].contains("+pkgver=2"), "{}", diff[0]);
+
+    assert_eq!(
+        fs::read_to_string(clone.join("PKGBUILD")).unwrap(),
+        "pkgname=local\n"
+    );
+    assert_eq!(
+        fs::read_to_string(clone.join("untracked")).unwrap(),
+        "untracked\n"
+    );
+    assert_eq!(git(&clone, &["status", "--porcelain"]), status);
+}
+
+#[test]
+fn diff_stat_skips_the_working_tree_and_patch() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let clone = env.clone_dir("foo");
+    let reflog = git(&clone, &["reflog"]);
+
+    let stat = env
+        .fetch()
+        .diff_stat(&["foo"], Color::Never)
+        .unwrap()
+        .remove(0);
+    assert!(stat.contains(" update\n"), "{}", stat);
+    assert!(stat.contains(" PKGBUILD | 1 +"), "{}", stat);
+    assert!(!stat.contains("Author:"), "{}", stat);
+    assert!(!stat.contains("+pkgver=2"), "{}", stat);
+
+    // The merge never resets HEAD.
+    assert_eq!(git(&clone, &["reflog"]), reflog);
+}
+
+/// Records whether `done` existed when the first byte was written.
+struct FirstWrite<'a> {
+    done: &'a Path,
+    written: Vec<u8>,
+    early: Option<bool>,
+}
+
+impl Write for FirstWrite<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let done = self.done;
+        self.early.get_or_insert_with(|| !done.exists());
+        self.written.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn write_diff_streams_output() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let done = env.dir.path().join("done");
+    let git = common::script(
+        &env,
+        "slow-log-git",
+        &format!(
+            "case \" $* \" in *\" log \"*) echo streamed; sleep 1; touch '{}' ;; esac\n\
+             exec git \"$@\"\n",
+            done.display()
+        ),
+    );
+    let fetch = Fetch { git, ..env.fetch() };
+
+    let mut out = FirstWrite {
+        done: &done,
+        written: Vec::new(),
+        early: None,
+    };
+    fetch.write_diff("foo", Color::Never, &mut out).unwrap();
+    assert_eq!(out.early, Some(true));
+    let written = String::from_utf8(out.written).unwrap();
+    assert!(written.starts_with("streamed\n"), "{}", written);
+    assert!(written.contains("+pkgver=2"), "{}", written);
+}
+
+#[test]
+fn conflicted_merges_are_not_reused() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let fetch = env.fetch();
+    fetch.accept(&["foo"], true).unwrap();
+
+    // Rewrite upstream so the seen commit conflicts with the new upstream HEAD.
+    let work = env.work("foo");
+    git(&work, &["reset", "-q", "--hard", "HEAD~1"]);
+    fs::write(work.join("PKGBUILD"), "pkgname=foo\npkgver=3\n").unwrap();
+    git(&work, &["commit", "-q", "-am", "rewrite"]);
+    git(&work, &["push", "-q", "--force", "origin", "HEAD:master"]);
+    fetch.download(&["foo"]).unwrap();
+
+    let diff = fetch.diff_safe(&["foo"], Color::Never).unwrap();
+    assert!(diff[0].contains("<<<<<<<"), "{}", diff[0]);
+    let res = fetch.diff(&["foo"], Color::Never);
+    assert!(res.is_err(), "{:?}", res);
+}
+
+#[test]
+fn seen_refs_are_independent() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let ours = env.fetch();
+    let theirs = Fetch {
+        seen_ref: "refs/mytool/seen".into(),
+        ..env.fetch()
+    };
+    ours.download(&["foo"]).unwrap();
+    ours.accept(&["foo"], false).unwrap();
+    assert!(ours.unseen(&["foo"]).unwrap().is_empty());
+    assert_eq!(theirs.unseen(&["foo"]).unwrap(), ["foo"]);
+
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=2\n", "update");
+    ours.download(&["foo"]).unwrap();
+    let diff = ours.diff(&["foo"], Color::Never).unwrap();
+    assert!(diff[0].contains("+pkgver=2"), "{}", diff[0]);
+    assert!(!diff[0].contains("+pkgname=foo"), "{}", diff[0]);
+    // Never having seen foo, the other tool is shown all of it.
+    let diff = theirs.diff(&["foo"], Color::Never).unwrap();
+    assert!(diff[0].contains("+pkgname=foo"), "{}", diff[0]);
+
+    theirs.accept(&["foo"], false).unwrap();
+    assert!(theirs.unseen(&["foo"]).unwrap().is_empty());
+    assert_eq!(ours.unseen(&["foo"]).unwrap(), ["foo"]);
+    let clone = env.clone_dir("foo");
+    assert_ne!(
+        git(&clone, &["rev-parse", "AUR_SEEN"]),
+        git(&clone, &["rev-parse", "refs/mytool/seen"])
+    );
+}
+
+#[test]
+fn diff_exclude_filters_paths() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+    fetch.accept(&["foo"], true).unwrap();
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=2\n", "pkgbuild");
+    env.push(
+        "foo",
+        ".SRCINFO",
+        "pkgbase = foo\n\tpkgver = 2\n",
+        "srcinfo",
+    );
+    env.push("foo", "deps.lock", "locked\n", "lock");
+    fetch.download(&["foo"]).unwrap();
+
+    let diffed = |exclude: &[&str]| {
+        let fetch = Fetch {
+            diff_exclude: exclude.iter().map(|e| e.to_string()).collect(),
+            ..env.fetch()
+        };
+        let diff = fetch.diff(&["foo"], Color::Never).unwrap().remove(0);
+        ["PKGBUILD", ".SRCINFO", "deps.lock"]
+            .iter()
+            .filter(|file| diff.contains(&format!("+++ b/{}", file)))
+            .copied()
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(diffed(&[".SRCINFO"]), ["PKGBUILD", "deps.lock"]);
+    assert_eq!(diffed(&["*.lock"]), ["PKGBUILD", ".SRCINFO"]);
+    assert_eq!(diffed(&[".SRCINFO", "*.lock"]), ["PKGBUILD"]);
+    assert_eq!(diffed(&[]), ["PKGBUILD", ".SRCINFO", "deps.lock"]);
+}
+
+#[test]
+fn diff_untracked_shows_new_files() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let clone = env.clone_dir("foo");
+    fs::write(clone.join("fix.patch"), "patch\n").unwrap();
+    fs::write(clone.join("notes.lock"), "notes\n").unwrap();
+    let fetch = Fetch {
+        diff_untracked: true,
+        diff_exclude: vec!["*.lock".into()],
+        ..env.fetch()
+    };
+
+    let diff = fetch.diff(&["foo"], Color::Never).unwrap().remove(0);
+    assert!(diff.contains(" fix.patch | 1 +"), "{}", diff);
+    assert!(
+        diff.contains("+++ b/fix.patch\n@@ -0,0 +1 @@\n+patch"),
+        "{}",
+        diff
+    );
+    assert!(!diff.contains("notes.lock"), "{}", diff);
+    assert_eq!(diff.matches("new file mode").count(), 1, "{}", diff);
+
+    let stat = fetch.diff_stat(&["foo"], Color::Never).unwrap().remove(0);
+    assert!(stat.contains(" fix.patch | 1 +"), "{}", stat);
+    assert!(!stat.contains("+++"), "{}", stat);
+
+    // The real index is left alone.
+    assert_eq!(
+        git(&clone, &["status", "--porcelain"]),
+        "?? fix.patch\n?? notes.lock"
+    );
+}
+
+#[test]
+fn download_and_diff_returns_a_result_per_package() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    env.add_pkg("bar");
+    env.add_pkg("baz");
+    let fetch = env.fetch();
+    fetch.download(&["baz"]).unwrap();
+    fetch.mark_seen(&["baz"]).unwrap();
+
+    let mut results = fetch
+        .download_and_diff(&["foo", "bar", "baz", "missing"], Color::Never)
+        .unwrap();
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    let pkgs = results
+        .iter()
+        .map(|(pkg, _)| pkg.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(pkgs, ["bar", "baz", "foo", "missing"]);
+
+    let bar = results[0].1.as_ref().unwrap().as_ref().unwrap();
+    assert!(bar.contains("+pkgname=bar"), "{}", bar);
+    assert_eq!(results[1].1.as_ref().unwrap(), &None);
+    let foo = results[2].1.as_ref().unwrap().as_ref().unwrap();
+    assert!(foo.contains("+pkgver=2"), "{}", foo);
+    assert!(results[3].1.is_err());
+}
+
+#[test]
+fn concurrent_worktree_diffs_do_not_share_a_worktree() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let fetch = Fetch {
+        use_worktrees: true,
+        merge_cache: MergeCache::with_capacity(0),
+        ..env.fetch()
+    };
+    let expected = fetch.diff(&["foo"], Color::Never).unwrap();
+
+    std::thread::scope(|scope| {
+        let diffs = (0..4)
+            .map(|_| scope.spawn(|| fetch.diff(&["foo"], Color::Never).unwrap()))
+            .collect::<Vec<_>>();
+        for diff in diffs {
+            assert_eq!(diff.join().unwrap(), expected);
+        }
+    });
+
+    // Each worktree is locked while in use, so every lock is free again once the diffs finish.
+    let git_dir = env.clone_dir("foo").join(".git");
+    let worktrees = fs::read_dir(&git_dir)
+        .unwrap()
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("aur-fetch-worktree") && !name.ends_with(".lock")
+        })
+        .count();
+    assert!(worktrees >= 1);
+    fetch.diff(&["foo"], Color::Never).unwrap();
+    assert!(!git_dir
+        .join(format!("aur-fetch-worktree-{}", worktrees))
+        .exists());
+}
+
+#[test]
+fn batch_status_counts_commits_since_seen() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let fetch = env.fetch();
+    // Merged but not yet seen, so nothing is left to merge but there is still a commit to review.
+    fetch.merge(&["foo"]).unwrap();
+
+    let report = fetch.batch_status(&["foo"]).unwrap().remove(0);
+    assert_eq!(fetch.ahead_behind("foo").unwrap(), (0, 0));
+    assert_eq!(report.commits_behind, 1);
+    assert_eq!(report.commits_behind, fetch.commits_behind("foo").unwrap());
+}
+
+#[test]
+fn diff_history_keeps_every_save() {
+    let env = Env::new();
+    seen_then_changed(&env);
+    let fetch = Fetch {
+        keep_diff_history: true,
+        ..env.fetch()
+    };
+    // A diff kept by an older version, named with whole seconds.
+    let dir = env.cache().join("diff/.history/foo");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("100.diff"), "old").unwrap();
+
+    for _ in 0..3 {
+        fetch.save_diffs(&["foo"]).unwrap();
+    }
+
+    let history = fetch.diff_history("foo").unwrap();
+    assert_eq!(history.len(), 4);
+    assert_eq!(history[0].1, dir.join("100.diff"));
+    assert!(history.windows(2).all(|w| w[0].0 < w[1].0));
+}
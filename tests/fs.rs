@@ -0,0 +1,222 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use aur_fetch::FileSystem;
+use common::Env;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Dir,
+    File(Vec<u8>),
+    Link(PathBuf),
+}
+
+/// Keeps everything in memory, except for links which may point to real files.
+#[derive(Debug, Default)]
+struct MemFs(Mutex<HashMap<PathBuf, Node>>);
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, path.display().to_string())
+}
+
+impl MemFs {
+    fn get(&self, path: &Path) -> Option<Node> {
+        self.0.lock().unwrap().get(path).cloned()
+    }
+
+    fn insert(&self, path: &Path, node: Node) -> io::Result<()> {
+        match path.parent().and_then(|parent| self.get(parent)) {
+            Some(Node::Dir) => (),
+            _ => return Err(not_found(path)),
+        }
+        self.0.lock().unwrap().insert(path.to_path_buf(), node);
+        Ok(())
+    }
+}
+
+impl FileSystem for MemFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut map = self.0.lock().unwrap();
+        for dir in path.ancestors() {
+            map.insert(dir.to_path_buf(), Node::Dir);
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, src: &Path, dest: &Path) -> io::Result<()> {
+        if self.exists(dest) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        self.insert(dest, Node::Link(src.to_path_buf()))
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.insert(path, Node::File(data.to_vec()))
+    }
+
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = self.read(path).unwrap_or_default();
+        file.extend(data);
+        self.write(path, &file)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.get(path) {
+            Some(Node::File(data)) => Ok(data),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if !matches!(self.get(path), Some(Node::Dir)) {
+            return Err(not_found(path));
+        }
+        let map = self.0.lock().unwrap();
+        Ok(map
+            .keys()
+            .filter(|p| p.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.get(path) {
+            Some(Node::Link(target)) => Ok(target),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        match self.get(path) {
+            Some(Node::File(_)) => true,
+            Some(Node::Link(target)) => self.is_file(&target) || target.is_file(),
+            _ => false,
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.get(path).is_some()
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.get(path) {
+            Some(Node::File(_)) | Some(Node::Link(_)) => {
+                self.0.lock().unwrap().remove(path);
+                Ok(())
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        if !matches!(self.get(path), Some(Node::Dir)) {
+            return Err(not_found(path));
+        }
+        self.0.lock().unwrap().retain(|p, _| !p.starts_with(path));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut map = self.0.lock().unwrap();
+        let moved = map
+            .keys()
+            .filter(|p| p.starts_with(from))
+            .cloned()
+            .collect::<Vec<_>>();
+        if moved.is_empty() {
+            return Err(not_found(from));
+        }
+        for path in moved {
+            let node = map.remove(&path).unwrap();
+            map.insert(to.join(path.strip_prefix(from).unwrap()), node);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn diff_dir_goes_through_fs() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let mem = Arc::new(MemFs::default());
+    let mut fetch = env.fetch();
+    fetch.fs = mem.clone();
+    fetch.record_provenance = true;
+    fetch.keep_diff_history = true;
+    let diff_dir = env.cache().join("diff");
+    let manifest = diff_dir.join("manifest");
+    mem.create_dir_all(&diff_dir).unwrap();
+
+    fetch.download_with_manifest(&["foo"], &manifest).unwrap();
+    assert!(String::from_utf8(mem.read(&manifest).unwrap())
+        .unwrap()
+        .contains("cloned foo"));
+    assert!(fetch.provenance("foo").unwrap().is_some());
+
+    fetch.save_diffs(&["foo"]).unwrap();
+    let diff = mem.read(&diff_dir.join("foo.diff")).unwrap();
+    assert!(String::from_utf8_lossy(&diff).contains("+pkgname=foo"));
+    let history = fetch.diff_history("foo").unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(mem.read(&history[0].1).unwrap(), diff);
+
+    let view = PathBuf::from("/view");
+    mem.create_dir_all(&view).unwrap();
+    let links = fetch.make_view_paths(&view, &["foo"], &["foo"]).unwrap();
+    assert_eq!(links.len(), 4);
+    assert!(mem.is_file(&view.join("foo.diff")));
+    assert!(mem.is_file(&view.join("foo.PKGBUILD")));
+
+    // Nothing outside of the clone dir touched the real filesystem.
+    assert!(!diff_dir.exists());
+    assert!(!view.exists());
+    assert!(env.clone_dir("foo").join("PKGBUILD").is_file());
+
+    fetch.remove(&["foo"]).unwrap();
+    assert!(!env.clone_dir("foo").exists());
+    assert!(!mem.exists(&diff_dir.join("foo.diff")));
+    assert!(fetch.provenance("foo").unwrap().is_none());
+    assert!(fetch.diff_history("foo").unwrap().is_empty());
+}
+
+#[test]
+fn relocate_moves_diff_dir_through_fs() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let mem = Arc::new(MemFs::default());
+    let mut fetch = env.fetch();
+    fetch.fs = mem.clone();
+    fetch.download(&["foo"]).unwrap();
+    fetch.save_diffs(&["foo"]).unwrap();
+
+    let root = env.dir.path().join("moved");
+    let moved = fetch.relocate(&root).unwrap();
+    assert!(root.join("clone/foo/PKGBUILD").is_file());
+    assert!(mem.is_file(&root.join("diff/foo.diff")));
+    assert!(!root.join("diff").exists());
+    assert_eq!(moved.orphaned_diffs().unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn relocate_failure_leaves_cache_in_place() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+    fetch.save_diffs(&["foo"]).unwrap();
+
+    // The diff dir can't be renamed over a dir that isn't empty.
+    let root = env.dir.path().join("moved");
+    fs::create_dir_all(root.join("diff")).unwrap();
+    fs::write(root.join("diff/other"), "").unwrap();
+
+    assert!(fetch.relocate(&root).is_err());
+    assert!(env.clone_dir("foo").join("PKGBUILD").is_file());
+    assert!(!root.join("clone").exists());
+    assert!(env.cache().join("diff/foo.diff").is_file());
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use aur_fetch::Error;
+use common::Env;
+
+#[test]
+fn check_git_reports_version() {
+    let env = Env::new();
+    let fetch = env.fetch();
+    let version = fetch.check_git().unwrap();
+    assert_eq!(
+        common::git(env.dir.path(), &["--version"]),
+        format!("git version {}", version)
+    );
+}
+
+#[test]
+fn missing_git_is_not_found() {
+    let env = Env::new();
+    let mut fetch = env.fetch();
+    let missing = env.dir.path().join("no-such-git");
+    fetch.git = missing.clone();
+
+    let res = fetch.check_git();
+    assert!(
+        matches!(&res, Err(Error::GitNotFound(git)) if *git == missing),
+        "{:?}",
+        res
+    );
+}
+
+#[test]
+fn other_programs_are_not_git() {
+    let env = Env::new();
+    let mut fetch = env.fetch();
+    fetch.git = common::script(&env, "not-git", "echo hello\n");
+
+    let res = fetch.check_git();
+    assert!(matches!(res, Err(Error::CommandFailed(_))), "{:?}", res);
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use std::fs;
+
+use aur_fetch::{ConflictPolicy, Error};
+use common::{git, Env};
+
+/// Sets up foo with a local commit that conflicts with the next upstream commit.
+fn conflicting(env: &Env) -> String {
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+    fetch.merge(&["foo"]).unwrap();
+
+    let clone = env.clone_dir("foo");
+    fs::write(clone.join("PKGBUILD"), "pkgname=local\n").unwrap();
+    git(&clone, &["commit", "-q", "-am", "local"]);
+    env.push("foo", "PKGBUILD", "pkgname=upstream\n", "upstream");
+    fetch.download(&["foo"]).unwrap();
+
+    git(&clone, &["rev-parse", "HEAD"])
+}
+
+#[test]
+fn conflict_aborts_by_default() {
+    let env = Env::new();
+    let head = conflicting(&env);
+    let fetch = env.fetch();
+    let clone = env.clone_dir("foo");
+
+    let res = fetch.merge(&["foo"]);
+    assert!(
+        matches!(&res, Err(Error::MergeConflict { pkg, files }) if pkg == "foo" && files == &["PKGBUILD"]),
+        "{:?}",
+        res
+    );
+
+    assert!(!clone.join(".git/rebase-merge").exists());
+    assert!(!clone.join(".git/rebase-apply").exists());
+    assert_eq!(git(&clone, &["status", "--porcelain"]), "");
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), head);
+    assert_eq!(
+        fs::read_to_string(clone.join("PKGBUILD")).unwrap(),
+        "pkgname=local\n"
+    );
+}
+
+#[test]
+fn conflict_can_be_left() {
+    let env = Env::new();
+    conflicting(&env);
+    let mut fetch = env.fetch();
+    fetch.conflict_policy = ConflictPolicy::Leave;
+    let clone = env.clone_dir("foo");
+
+    let res = fetch.merge(&["foo"]);
+    assert!(matches!(res, Err(Error::MergeConflict { .. })), "{:?}", res);
+    assert!(clone.join(".git/rebase-merge").exists());
+
+    // Merging again with the default policy cleans up the earlier merge.
+    let res = env.fetch().merge(&["foo"]);
+    assert!(matches!(res, Err(Error::MergeConflict { .. })), "{:?}", res);
+    assert!(!clone.join(".git/rebase-merge").exists());
+    assert_eq!(git(&clone, &["status", "--porcelain"]), "");
+}
+
+/// Downloads foo and bar and pushes a change to each of them.
+fn behind(env: &Env) {
+    let fetch = env.fetch();
+    for pkg in ["foo", "bar"] {
+        env.add_pkg(pkg);
+        fetch.download(&[pkg]).unwrap();
+        let pkgbuild = format!("pkgname={}\npkgver=2\n", pkg);
+        env.push(pkg, "PKGBUILD", &pkgbuild, "update");
+    }
+    fetch.download(&["foo", "bar"]).unwrap();
+}
+
+#[test]
+fn merge_progress_validates_names_first() {
+    let env = Env::new();
+    behind(&env);
+    let fetch = env.fetch();
+    let head = git(&env.clone_dir("foo"), &["rev-parse", "HEAD"]);
+
+    let res = fetch.merge_progress(&["foo", "../bar"], |_| ());
+    assert!(
+        matches!(res, Err(Error::InvalidPackageName(_))),
+        "{:?}",
+        res
+    );
+    assert_eq!(git(&env.clone_dir("foo"), &["rev-parse", "HEAD"]), head);
+}
+
+#[test]
+fn merge_progress_uses_merge_jobs() {
+    let env = Env::new();
+    behind(&env);
+    let mut fetch = env.fetch();
+    fetch.merge_jobs = 2;
+
+    let lines = std::cell::RefCell::new(Vec::new());
+    fetch
+        .merge_progress(&["foo", "bar"], |cb| {
+            lines.borrow_mut().push(cb.pkg.to_string())
+        })
+        .unwrap();
+
+    let lines = lines.into_inner();
+    for pkg in ["foo", "bar"] {
+        assert!(lines.iter().any(|line| line == pkg), "{:?}", lines);
+        let pkgbuild = fs::read_to_string(env.clone_dir(pkg).join("PKGBUILD")).unwrap();
+        assert!(pkgbuild.contains("pkgver=2"), "{}", pkgbuild);
+    }
+}
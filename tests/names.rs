@@ -0,0 +1,63 @@
+mod common;
+
+use std::collections::HashMap;
+
+use aur_fetch::{Error, ReviewState};
+use common::Env;
+
+const INVALID: &[&str] = &["..", "../escape", "a/b", "/escape", "", "-escape", "."];
+
+fn assert_invalid<T: std::fmt::Debug>(name: &str, res: aur_fetch::Result<T>) {
+    assert!(
+        matches!(&res, Err(Error::InvalidPackageName(pkg)) if pkg == name),
+        "{:?} gave {:?}",
+        name,
+        res
+    );
+}
+
+#[test]
+fn invalid_names_are_rejected() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+
+    for &name in INVALID {
+        let pkgs = [name];
+        assert_invalid(name, fetch.pkg_dir(name));
+        assert_invalid(name, fetch.diff_path(name));
+        assert_invalid(name, fetch.download(&pkgs));
+        assert_invalid(name, fetch.download(&["foo", name]));
+        assert_invalid(name, fetch.has_diff(&pkgs));
+        assert_invalid(name, fetch.unseen(&pkgs));
+        assert_invalid(name, fetch.status(name));
+        assert_invalid(name, fetch.batch_status(&pkgs));
+        assert_invalid(name, fetch.diff(&pkgs, false));
+        assert_invalid(name, fetch.print_diff(name));
+        assert_invalid(name, fetch.save_diffs(&pkgs));
+        assert_invalid(name, fetch.merge(&pkgs));
+        assert_invalid(name, fetch.merge_progress(&pkgs, |_| ()));
+        assert_invalid(name, fetch.mark_seen(&pkgs));
+        assert_invalid(name, fetch.accept(&pkgs, true));
+        assert_invalid(name, fetch.accept_cb(&pkgs, false, |_| ()));
+        assert_invalid(name, fetch.reset_to_upstream(&pkgs));
+        assert_invalid(name, fetch.fetch_file(name, "PKGBUILD"));
+        assert_invalid(name, fetch.checkout_file(name, "PKGBUILD", "HEAD"));
+        assert_invalid(name, fetch.remove(&pkgs));
+        assert_invalid(name, fetch.clean_untracked(&pkgs));
+
+        let state = ReviewState {
+            seen: HashMap::from([(name.to_string(), "HEAD".to_string())]),
+        };
+        assert_invalid(name, fetch.import_state(&state));
+
+        assert!(!fetch.is_git_repo(name));
+    }
+
+    // Nothing may be created or removed outside of the clone dir.
+    assert!(!env.dir.path().join("cache/escape").exists());
+    assert!(!env.dir.path().join("escape").exists());
+    assert!(!std::path::Path::new("/escape").exists());
+    assert!(env.clone_dir("foo").join("PKGBUILD").exists());
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use aur_fetch::{Color, Repo};
+use common::{git, Env};
+
+fn pinned(env: &Env, reference: &str) -> Vec<Repo> {
+    let mut url = env.aur_url();
+    url.set_path(&format!("{}foo", url.path()));
+    vec![Repo {
+        url,
+        name: "foo".to_string(),
+        reference: Some(reference.to_string()),
+        branch: None,
+    }]
+}
+
+/// Sets up foo with a v1 tag and a stable branch one commit behind master.
+fn setup(env: &Env) -> String {
+    env.add_pkg("foo");
+    let work = env.work("foo");
+    git(&work, &["tag", "-a", "-m", "v1", "v1"]);
+    git(&work, &["push", "-q", "origin", "v1", "HEAD:stable"]);
+    let pin = git(&work, &["rev-parse", "HEAD"]);
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=2\n", "master");
+    pin
+}
+
+/// Checks the pinned repo ignores master and only moves with the pin.
+fn check(env: &Env, reference: &str, pin: &str) {
+    let fetch = env.fetch();
+    let clone = env.clone_dir("foo");
+    let repos = pinned(env, reference);
+
+    fetch.download_repos_cb(&repos, |_| ()).unwrap();
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), pin);
+    assert_eq!(fetch.upstream_head("foo").unwrap(), pin);
+    fetch.accept(&["foo"], true).unwrap();
+
+    env.push("foo", "PKGBUILD", "pkgname=foo\npkgver=3\n", "master again");
+    fetch.download_repos_cb(&repos, |_| ()).unwrap();
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), pin);
+    assert_eq!(fetch.upstream_head("foo").unwrap(), pin);
+    assert!(fetch.unseen(&["foo"]).unwrap().is_empty());
+    assert!(fetch.has_diff(&["foo"]).unwrap().is_empty());
+    fetch.merge(&["foo"]).unwrap();
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), pin);
+}
+
+#[test]
+fn pinned_to_tag() {
+    let env = Env::new();
+    let pin = setup(&env);
+    check(&env, "v1", &pin);
+}
+
+#[test]
+fn pinned_to_commit() {
+    let env = Env::new();
+    let pin = setup(&env);
+    check(&env, &pin, &pin);
+}
+
+#[test]
+fn pinned_to_branch() {
+    let env = Env::new();
+    let pin = setup(&env);
+    check(&env, "stable", &pin);
+
+    // Moving the branch moves the pin.
+    let work = env.work("foo");
+    git(&work, &["push", "-q", "origin", "HEAD:stable"]);
+    let fetch = env.fetch();
+    fetch
+        .download_repos_cb(&pinned(&env, "stable"), |_| ())
+        .unwrap();
+    let head = git(&work, &["rev-parse", "HEAD"]);
+    assert_eq!(fetch.upstream_head("foo").unwrap(), head);
+    assert_eq!(fetch.unseen(&["foo"]).unwrap(), ["foo"]);
+    let diff = fetch.diff(&["foo"], Color::Never).unwrap();
+    assert!(diff[0].contains("+pkgver=3"), "{}", diff[0]);
+    fetch.merge(&["foo"]).unwrap();
+    assert_eq!(git(&env.clone_dir("foo"), &["rev-parse", "HEAD"]), head);
+}
+
+#[test]
+fn unpinned_follows_master() {
+    let env = Env::new();
+    let pin = setup(&env);
+    let fetch = env.fetch();
+    fetch
+        .download_repos_cb(&pinned(&env, "v1"), |_| ())
+        .unwrap();
+    assert_eq!(fetch.upstream_head("foo").unwrap(), pin);
+
+    fetch.download(&["foo"]).unwrap();
+    let master = git(&env.work("foo"), &["rev-parse", "HEAD"]);
+    assert_eq!(fetch.upstream_head("foo").unwrap(), master);
+    fetch.merge(&["foo"]).unwrap();
+    assert_eq!(git(&env.clone_dir("foo"), &["rev-parse", "HEAD"]), master);
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::Env;
+
+const SRCINFO: &str = "pkgbase = foo
+\tpkgver = 1
+\tpkgrel = 1
+\tarch = x86_64
+\tarch = aarch64
+\tmakedepends = make
+\tcheckdepends = check
+\tdepends = base
+\tdepends_x86_64 = lib64
+\tsource = foo::git+https://example.com/foo.git
+
+pkgname = foo
+\tdepends = base
+\tdepends = foo-only
+
+pkgname = foo-docs
+\tdepends = docs
+";
+
+#[cfg(feature = "srcinfo")]
+#[test]
+fn dependencies_of_split_packages() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    env.push("foo", ".SRCINFO", SRCINFO, "split");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+
+    let mut deps = fetch.dependencies("foo").unwrap();
+    deps.sort();
+    assert_eq!(deps, ["base", "check", "docs", "foo-only", "lib64", "make"]);
+}
+
+#[test]
+fn vcs_and_missing_srcinfo() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    env.push("foo", ".SRCINFO", SRCINFO, "split");
+    env.add_pkg("plain");
+    env.push(
+        "plain",
+        ".SRCINFO",
+        "pkgbase = plain\n\tpkgver = 1\n\tpkgrel = 1\n\tarch = any\n\tsource_x86_64 = https://example.com/plain.tar.gz\n\npkgname = plain\n",
+        "plain",
+    );
+    env.add_pkg("bar");
+    let fetch = env.fetch();
+    fetch.download(&["foo", "plain", "bar"]).unwrap();
+    assert!(fetch.is_vcs("foo").unwrap());
+    assert!(!fetch.is_vcs("plain").unwrap());
+
+    common::git(&env.work("bar"), &["rm", "-q", ".SRCINFO"]);
+    common::git(&env.work("bar"), &["commit", "-q", "-m", "rm"]);
+    common::git(&env.work("bar"), &["push", "-q", "origin", "HEAD:master"]);
+    fetch.download(&["bar"]).unwrap();
+    assert!(!fetch.is_vcs("bar").unwrap());
+    #[cfg(feature = "srcinfo")]
+    assert!(fetch.dependencies("bar").unwrap().is_empty());
+}
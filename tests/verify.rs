@@ -0,0 +1,74 @@
+mod common;
+
+use std::cell::RefCell;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use aur_fetch::{Error, Fetch};
+use common::Env;
+
+fn verifying(env: &Env, command: &[&str]) -> Fetch {
+    env.add_pkg("foo");
+    let mut fetch = env.fetch();
+    fetch.verify_command = Some(command.iter().map(|s| s.to_string()).collect());
+    fetch.download(&["foo"]).unwrap();
+    fetch
+}
+
+fn is_seen(env: &Env, fetch: &Fetch) -> bool {
+    Command::new("git")
+        .current_dir(env.clone_dir("foo"))
+        .args(["rev-parse", "-q", "--verify", &fetch.seen_ref])
+        .output()
+        .unwrap()
+        .status
+        .success()
+}
+
+#[test]
+fn passing_verify_marks_seen() {
+    let env = Env::new();
+    let fetch = verifying(&env, &["sh", "-c", "test -f PKGBUILD && echo looks good"]);
+
+    let calls = RefCell::new(Vec::new());
+    fetch
+        .accept_cb(&["foo"], true, |cb| {
+            calls
+                .borrow_mut()
+                .push((cb.pkg.to_string(), cb.n, cb.output.to_string()))
+        })
+        .unwrap();
+    assert_eq!(
+        calls.into_inner(),
+        [("foo".to_string(), 1, "looks good".to_string())]
+    );
+    assert!(is_seen(&env, &fetch));
+}
+
+#[test]
+fn failing_verify_reports_its_output() {
+    let env = Env::new();
+    let fetch = verifying(&env, &["sh", "-c", "echo PKGBUILD is bad; exit 1"]);
+
+    match fetch.mark_seen(&["foo"]) {
+        Err(Error::CommandFailed(e)) => {
+            assert_eq!(e.command.to_str(), Some("sh"));
+            assert_eq!(e.stderr.as_deref(), Some("PKGBUILD is bad"));
+        }
+        res => panic!("{:?}", res),
+    }
+    assert!(!is_seen(&env, &fetch));
+}
+
+#[test]
+fn verify_is_killed_after_command_timeout() {
+    let env = Env::new();
+    let mut fetch = verifying(&env, &["sh", "-c", "sleep 30"]);
+    fetch.command_timeout = Some(Duration::from_millis(500));
+
+    let start = Instant::now();
+    let res = fetch.mark_seen(&["foo"]);
+    assert!(matches!(res, Err(Error::Timeout { .. })), "{:?}", res);
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(!is_seen(&env, &fetch));
+}
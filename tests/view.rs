@@ -0,0 +1,57 @@
+mod common;
+
+use std::fs;
+use std::io;
+use std::os::unix;
+
+use aur_fetch::Error;
+use common::Env;
+
+#[test]
+fn view_is_idempotent() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+    fetch.save_diffs(&["foo"]).unwrap();
+    let view = env.dir.path().join("view");
+    fs::create_dir(&view).unwrap();
+
+    let links = fetch
+        .make_view_paths(&view, &["foo", "foo"], &["foo", "foo"])
+        .unwrap();
+    assert_eq!(links.len(), 4);
+    let again = fetch.make_view_paths(&view, &["foo"], &["foo"]).unwrap();
+    assert_eq!(links, again);
+
+    // Stale links are replaced.
+    fs::remove_file(view.join("foo.PKGBUILD")).unwrap();
+    unix::fs::symlink("/nonexistent", view.join("foo.PKGBUILD")).unwrap();
+    fetch.make_view(&view, &["foo"], &["foo"]).unwrap();
+    assert_eq!(
+        fs::read_link(view.join("foo.PKGBUILD")).unwrap(),
+        env.clone_dir("foo").join("PKGBUILD")
+    );
+}
+
+#[test]
+fn view_keeps_real_files() {
+    let env = Env::new();
+    env.add_pkg("foo");
+    let fetch = env.fetch();
+    fetch.download(&["foo"]).unwrap();
+    let view = env.dir.path().join("view");
+    fs::create_dir(&view).unwrap();
+    fs::write(view.join("foo.PKGBUILD"), "mine").unwrap();
+
+    let res = fetch.make_view(&view, &["foo"], &[] as &[&str]);
+    assert!(
+        matches!(&res, Err(Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists),
+        "{:?}",
+        res
+    );
+    assert_eq!(
+        fs::read_to_string(view.join("foo.PKGBUILD")).unwrap(),
+        "mine"
+    );
+}